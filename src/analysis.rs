@@ -0,0 +1,238 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::map::{Direction, FACES};
+use crate::tile::TileSet;
+
+// Static checks of a tileset's adjacency rules that don't need a map
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    // Tiles that can't go in any cell of any map. A tile without neighbours
+    // in one direction still fits on the face of the map on that side, but
+    // not once it also lacks them in the opposite direction
+    pub unplaceable: Vec<u32>,
+    // Directions in which a tile allows no neighbour at all, so it can only
+    // go on the face of the map on that side
+    pub dead_ends: Vec<(u32, Direction)>,
+    // Source allows target as its neighbour in direction, but target doesn't
+    // allow source in the opposite direction, so neither is allowed
+    pub asymmetric: Vec<(u32, Direction, u32)>,
+    // Tiles that can reach each other through allowed neighbours, more than
+    // one group means no map can hold tiles of two different groups
+    pub groups: Vec<Vec<u32>>,
+    names: Vec<String>,
+}
+
+impl Report {
+    // Whether every check passed, dead ends aside since they are expected on
+    // tiles that belong on a face, like a bottom layer
+    pub fn is_clean(&self) -> bool {
+        self.unplaceable.is_empty() && self.asymmetric.is_empty() && self.groups.len() <= 1
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = |tile_id: &u32| self.names[*tile_id as usize].as_str();
+        for tile_id in self.unplaceable.iter() {
+            writeln!(f, "{} can never be placed", name(tile_id))?;
+        }
+        for (tile_id, d) in self.dead_ends.iter() {
+            writeln!(f, "{} allows nothing {}", name(tile_id), d.relative())?;
+        }
+        for (source_id, d, target_id) in self.asymmetric.iter() {
+            writeln!(
+                f,
+                "{} allows {} {}, but not the other way around",
+                name(source_id),
+                name(target_id),
+                d.relative(),
+            )?;
+        }
+        if self.groups.len() > 1 {
+            for (i, group) in self.groups.iter().enumerate() {
+                let names: Vec<&str> = group.iter().map(name).collect();
+                writeln!(f, "group {}: {}", i + 1, names.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn analyse(tileset: &TileSet) -> Report {
+    let tile_count = tileset.len() as u32;
+
+    let mut dead_ends = Vec::new();
+    for tile_id in 0..tile_count {
+        for direction in FACES {
+            if tileset.allowed(tile_id, direction).is_empty() {
+                dead_ends.push((tile_id, direction));
+            }
+        }
+    }
+
+    let mut asymmetric = Vec::new();
+    for source_id in 0..tile_count {
+        for direction in FACES {
+            for target_id in 0..tile_count {
+                let forward = tileset.update(source_id, direction)[target_id as usize];
+                let backward = tileset.update(target_id, direction.opposite())[source_id as usize];
+                if forward >= 0.0 && backward < 0.0 {
+                    asymmetric.push((source_id, direction, target_id));
+                }
+            }
+        }
+    }
+
+    // Removes tiles left without placeable neighbours on both sides of an
+    // axis until none are left to remove
+    let mut placeable = vec![true; tile_count as usize];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for tile_id in 0..tile_count {
+            if !placeable[tile_id as usize] {
+                continue;
+            }
+            let stuck = |direction: Direction| {
+                !tileset
+                    .allowed(tile_id, direction)
+                    .iter()
+                    .any(|target_id| placeable[*target_id as usize])
+            };
+            if FACES.iter().any(|direction| stuck(*direction) && stuck(direction.opposite())) {
+                placeable[tile_id as usize] = false;
+                changed = true;
+            }
+        }
+    }
+    let unplaceable = (0..tile_count).filter(|tile_id| !placeable[*tile_id as usize]).collect();
+
+    // Connected components over allowed pairs in any direction
+    let mut group_ids: Vec<Option<usize>> = vec![None; tile_count as usize];
+    let mut groups = Vec::new();
+    for first_id in 0..tile_count {
+        if group_ids[first_id as usize].is_some() {
+            continue;
+        }
+        let mut group = Vec::new();
+        let mut stack = vec![first_id];
+        group_ids[first_id as usize] = Some(groups.len());
+        while let Some(tile_id) = stack.pop() {
+            group.push(tile_id);
+            for direction in FACES {
+                for target_id in tileset.allowed(tile_id, direction) {
+                    if group_ids[*target_id as usize].is_none() {
+                        group_ids[*target_id as usize] = Some(groups.len());
+                        stack.push(*target_id);
+                    }
+                }
+            }
+        }
+        group.sort_unstable();
+        groups.push(group);
+    }
+
+    Report {
+        unplaceable: unplaceable,
+        dead_ends: dead_ends,
+        asymmetric: asymmetric,
+        groups: groups,
+        names: (0..tile_count).map(|tile_id| tileset.name(tile_id).clone()).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::tests::patterns;
+    use crate::tile::{Template, BAN};
+
+    // Tiles that ban every neighbour but the pairs allowed by rules, each of
+    // which also allows its mirror image unless one_way
+    fn tileset(names: &[&str], rules: &[(u32, Direction, u32)], one_way: bool) -> TileSet {
+        let tiles: Vec<(&str, f32)> = names.iter().map(|name| (*name, 1.0)).collect();
+        patterns(&tiles, &FACES, |source, direction, target| {
+            let mirrored = !one_way && rules.contains(&(target, direction.opposite(), source));
+            if rules.contains(&(source, direction, target)) || mirrored {
+                1.0
+            } else {
+                BAN
+            }
+        })
+    }
+
+    // Rules allowing a tile next to itself in every direction
+    fn alone(tile_id: u32) -> Vec<(u32, Direction, u32)> {
+        FACES.iter().map(|direction| (tile_id, *direction, tile_id)).collect()
+    }
+
+    #[test]
+    fn road_template_is_clean() {
+        let report = analyse(&TileSet::gen(Template::Road));
+        assert!(report.is_clean(), "{}", report);
+    }
+
+    #[test]
+    fn finds_dead_ends() {
+        // Floor only ever has air above it, so nothing goes below floor and
+        // nothing above air
+        let mut rules = vec![(0, Direction::Up, 1)];
+        for direction in crate::map::SIDES {
+            rules.push((0, direction, 0));
+            rules.push((1, direction, 1));
+        }
+        let report = analyse(&tileset(&["floor", "air"], &rules, false));
+        assert_eq!(report.dead_ends, [(0, Direction::Down), (1, Direction::Up)]);
+        assert!(report.unplaceable.is_empty());
+        assert!(report.is_clean());
+        assert_eq!(report.to_string(), "floor allows nothing below\nair allows nothing above\n");
+    }
+
+    #[test]
+    fn finds_unplaceable_tiles() {
+        // Post allows nothing east or west, so it fits in no cell with
+        // neighbours on both sides. Sign only has post to its north and
+        // south, so it falls with post on the next pass
+        let mut rules = alone(0);
+        for direction in [Direction::East, Direction::West, Direction::Up, Direction::Down] {
+            rules.push((1, direction, 0));
+        }
+        rules.push((1, Direction::North, 2));
+        rules.push((1, Direction::South, 2));
+        for direction in [Direction::North, Direction::South, Direction::Up, Direction::Down] {
+            rules.push((2, direction, 0));
+        }
+        let tileset = tileset(&["grass", "sign", "post"], &rules, false);
+        let report = analyse(&tileset);
+        assert_eq!(report.unplaceable, [1, 2]);
+        assert!(report.dead_ends.contains(&(2, Direction::East)) && !report.dead_ends.iter().any(|(id, _)| *id == 1));
+        assert!(!report.is_clean());
+        assert!(report.to_string().starts_with("sign can never be placed\npost can never be placed\n"));
+    }
+
+    #[test]
+    fn finds_asymmetric_rules() {
+        let mut rules = alone(0);
+        rules.extend(alone(1));
+        rules.push((0, Direction::East, 1));
+        let report = analyse(&tileset(&["grass", "road"], &rules, true));
+        assert_eq!(report.asymmetric, [(0, Direction::East, 1)]);
+        assert!(report.to_string().contains("grass allows road to the east, but not the other way around"));
+        // Neither tile may go next to the other, so they fall apart
+        assert_eq!(report.groups, [vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn finds_disconnected_groups() {
+        let mut rules = alone(0);
+        rules.extend(alone(1));
+        rules.extend(alone(2));
+        rules.push((0, Direction::Up, 2));
+        let report = analyse(&tileset(&["bedrock", "lava", "dirt"], &rules, false));
+        assert_eq!(report.groups, [vec![0, 2], vec![1]]);
+        assert!(report.asymmetric.is_empty() && report.unplaceable.is_empty());
+        assert!(!report.is_clean());
+        assert_eq!(report.to_string(), "group 1: bedrock, dirt\ngroup 2: lava\n");
+    }
+}
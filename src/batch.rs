@@ -0,0 +1,130 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::map::{GenerationError, Generation, Map, Options};
+use crate::tile::TileSet;
+
+// Parallel generation of independent maps. Every job is generated exactly
+// like Map::gen would with the same arguments, so the output doesn't depend
+// on the number of threads or on which thread picks up which job.
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub width: u32,
+    pub depth: u32,
+    pub height: u32,
+    pub seed: u64,
+    pub options: Options,
+}
+
+// Outcome of a job, a successful Generation also counts its attempts and steps
+pub struct Report {
+    pub result: Result<Generation, GenerationError>,
+    // Wall time the job took on its thread
+    pub elapsed: Duration,
+}
+
+// Generates every job on up to threads threads, 0 uses one per core.
+// Reports are in the same order as the jobs
+pub fn gen(tileset: impl Into<Arc<TileSet>>, jobs: &[Job], threads: usize) -> Vec<Report> {
+    let tileset = tileset.into();
+    run(jobs, threads, |job| {
+        let start = Instant::now();
+        let result = Map::gen(
+            job.width,
+            job.depth,
+            job.height,
+            tileset.clone(),
+            job.seed,
+            job.options.clone(),
+        );
+        Report {
+            result: result,
+            elapsed: start.elapsed(),
+        }
+    })
+}
+
+// Calls f on every item on up to threads scoped threads, each taking the next
+// unclaimed item when it is done with the last. Results are in item order
+pub fn run<T: Sync, R: Send>(items: &[T], threads: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let threads = match threads {
+        0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        threads => threads,
+    };
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, R)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(items.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= items.len() {
+                            return results;
+                        }
+                        results.push((index, f(&items[index])));
+                    }
+                })
+            })
+            .collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::Propagation;
+    use crate::tile::Template;
+
+    fn observations(map: &Map) -> Vec<Option<u32>> {
+        let mut observations = Vec::new();
+        for z in 0..6 {
+            for y in 0..8 {
+                for x in 0..8 {
+                    observations.push(map.observation(x, y, z).unwrap());
+                }
+            }
+        }
+        observations
+    }
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let options = Options {
+            propagation: Propagation::Hard,
+            max_backtracks: 16,
+            attempts: 4,
+            ..Options::default()
+        };
+        let jobs: Vec<Job> = (0..6)
+            .map(|seed| Job {
+                width: 8,
+                depth: 8,
+                height: 6,
+                seed: seed,
+                options: options.clone(),
+            })
+            .collect();
+        let tileset = Arc::new(TileSet::gen(Template::Road));
+        let sequential = gen(tileset.clone(), &jobs, 1);
+        let parallel = gen(tileset.clone(), &jobs, 0);
+        for (job, (a, b)) in jobs.iter().zip(sequential.iter().zip(parallel.iter())) {
+            let expected = Map::gen(8, 8, 6, tileset.clone(), job.seed, job.options.clone()).unwrap();
+            assert_eq!(observations(&a.result.as_ref().unwrap().map), observations(&expected.map));
+            assert_eq!(observations(&b.result.as_ref().unwrap().map), observations(&expected.map));
+        }
+    }
+
+    #[test]
+    fn run_keeps_item_order() {
+        let items: Vec<u32> = (0..100).collect();
+        assert_eq!(run(&items, 7, |item| item * 2), items.iter().map(|item| item * 2).collect::<Vec<_>>());
+        assert!(run(&[] as &[u32], 0, |item| *item).is_empty());
+    }
+}
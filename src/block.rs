@@ -0,0 +1,235 @@
+use std::sync::Arc;
+
+use crate::map::{Anchor, Boundaries, Boundary, Direction, GenerationError, Map, Options};
+use crate::math::mix;
+use crate::tile::TileSet;
+use crate::world::Chunk;
+
+// Modifying in blocks: the map is collapsed one overlapping block at a time
+// instead of in a single wave. Each block is generated as a map of its own,
+// one cell larger on every side so that the tiles already decided around it
+// can be anchored, and the part of the block overlapping earlier blocks is
+// generated again. A contradiction only resets and retries the block it
+// happened in, so a large map doesn't fail as a whole whenever any part of it
+// does. When the retries of a block run out of attempts too, the tiles
+// decided around it are likely what rules it out, so each retry widens the
+// block by a cell on every side and generates those again as well.
+//
+// Blocks go in scanline order, x then y then z. Periodic boundaries are
+// treated as open.
+
+#[derive(Debug, Clone, Copy)]
+pub struct Blocks {
+    // Cells of a block along each axis
+    pub size: (u32, u32, u32),
+    // Cells a block shares with the previous one along each axis
+    pub overlap: u32,
+    // Times a block that contradicts is widened and generated again
+    pub retries: u32,
+}
+
+impl Default for Blocks {
+    fn default() -> Self {
+        Blocks {
+            size: (16, 16, 16),
+            overlap: 4,
+            retries: 4,
+        }
+    }
+}
+
+pub fn gen(
+    width: u32,
+    depth: u32,
+    height: u32,
+    tileset: impl Into<Arc<TileSet>>,
+    seed: u64,
+    options: Options,
+    blocks: Blocks,
+) -> Result<Chunk, GenerationError> {
+    let tileset = tileset.into();
+    let mut tiles: Vec<Option<u32>> = vec![None; (width * depth * height) as usize];
+    let (size, map) = (blocks.size, (width, depth, height));
+    let steps = |length: u32, size: u32| {
+        let step = size.saturating_sub(blocks.overlap).max(1);
+        let mut starts: Vec<u32> = (0..length.saturating_sub(size)).step_by(step as usize).collect();
+        starts.push(length.saturating_sub(size));
+        starts
+    };
+    let mut block_id = 0;
+    for z0 in steps(height, size.2) {
+        for y0 in steps(depth, size.1) {
+            for x0 in steps(width, size.0) {
+                let block = (
+                    (x0, y0, z0),
+                    ((x0 + size.0).min(width), (y0 + size.1).min(depth), (z0 + size.2).min(height)),
+                );
+                let mut retry = 0;
+                loop {
+                    let block_seed = mix(seed ^ block_id ^ ((retry as u64) << 32));
+                    let widened = widen(block, retry, map);
+                    match regenerate(&mut tiles, map, &tileset, block_seed, &options, widened) {
+                        Err(GenerationError::Contradiction(_)) | Err(GenerationError::Anchors(_))
+                            if retry < blocks.retries =>
+                        {
+                            retry += 1;
+                        }
+                        result => break result?,
+                    }
+                }
+                block_id += 1;
+            }
+        }
+    }
+    let tiles = tiles.into_iter().map(|tile| tile.unwrap()).collect();
+    Ok(Chunk::new(width, depth, height, tileset, tiles))
+}
+
+// The block grown by the given number of cells on every side, without leaving the map
+fn widen(
+    (min, max): ((u32, u32, u32), (u32, u32, u32)),
+    by: u32,
+    (width, depth, height): (u32, u32, u32),
+) -> ((u32, u32, u32), (u32, u32, u32)) {
+    (
+        (min.0.saturating_sub(by), min.1.saturating_sub(by), min.2.saturating_sub(by)),
+        ((max.0 + by).min(width), (max.1 + by).min(depth), (max.2 + by).min(height)),
+    )
+}
+
+// Generates the cells from min up to max again, keeping the rest of the map.
+// The tiles are left as they were when the block contradicts
+fn regenerate(
+    tiles: &mut [Option<u32>],
+    (width, depth, height): (u32, u32, u32),
+    tileset: &Arc<TileSet>,
+    seed: u64,
+    options: &Options,
+    (min, max): ((u32, u32, u32), (u32, u32, u32)),
+) -> Result<(), GenerationError> {
+    let id = |x: u32, y: u32, z: u32| ((z * depth + y) * width + x) as usize;
+    // The block together with the ring of cells around it inside the map
+    let start = (min.0.saturating_sub(1), min.1.saturating_sub(1), min.2.saturating_sub(1));
+    let end = ((max.0 + 1).min(width), (max.1 + 1).min(depth), (max.2 + 1).min(height));
+    let inside = |x: u32, y: u32, z: u32| {
+        (min.0..max.0).contains(&x) && (min.1..max.1).contains(&y) && (min.2..max.2).contains(&z)
+    };
+
+    let mut anchors = Vec::new();
+    for z in start.2..end.2 {
+        for y in start.1..end.1 {
+            for x in start.0..end.0 {
+                let (bx, by, bz) = (x - start.0, y - start.1, z - start.2);
+                match tiles[id(x, y, z)] {
+                    Some(tile_id) if !inside(x, y, z) => anchors.push(Anchor::tile(bx, by, bz, tile_id)),
+                    _ => {
+                        for anchor in options.anchors.iter() {
+                            if (anchor.x, anchor.y, anchor.z) == (x, y, z) {
+                                anchors.push(Anchor::restrict(bx, by, bz, anchor.tiles.clone()));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    // Faces of the block that lie on the faces of the map keep their
+    // boundaries, the others are bounded by the ring
+    let face = |direction: Direction, on_face: bool| match options.boundaries.get(direction) {
+        Boundary::Fixed(tile_id) if on_face => Boundary::Fixed(tile_id),
+        _ => Boundary::Open,
+    };
+    let boundaries = Boundaries {
+        east: face(Direction::East, end.0 == width),
+        west: face(Direction::West, start.0 == 0),
+        north: face(Direction::North, end.1 == depth),
+        south: face(Direction::South, start.1 == 0),
+        up: face(Direction::Up, end.2 == height),
+        down: face(Direction::Down, start.2 == 0),
+    };
+    let options = Options {
+        anchors: anchors,
+        boundaries: boundaries,
+        ..options.clone()
+    };
+
+    let (block_width, block_depth, block_height) = (end.0 - start.0, end.1 - start.1, end.2 - start.2);
+    let generation = Map::gen(block_width, block_depth, block_height, tileset.clone(), seed, options)?;
+    for z in min.2..max.2 {
+        for y in min.1..max.1 {
+            for x in min.0..max.0 {
+                tiles[id(x, y, z)] = generation.map.observation(x - start.0, y - start.1, z - start.2)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::tests::colours;
+    use crate::map::{Propagation, FACES};
+    use crate::tile::Template;
+
+    // Whether every pair of neighbouring tiles in the chunk is allowed
+    fn consistent(chunk: &Chunk, tileset: &TileSet, (width, depth, height): (u32, u32, u32)) -> bool {
+        (0..width * depth * height).all(|id| {
+            let (x, y, z) = (id % width, id / width % depth, id / (width * depth));
+            FACES.into_iter().all(|direction| {
+                let (dx, dy, dz) = direction.offset();
+                let (nx, ny, nz) = (x as i32 + dx, y as i32 + dy, z as i32 + dz);
+                if nx < 0 || ny < 0 || nz < 0 || nx >= width as i32 || ny >= depth as i32 || nz >= height as i32 {
+                    return true;
+                }
+                let allowed = tileset.allowed(chunk.tile(x, y, z), direction);
+                allowed.contains(&chunk.tile(nx as u32, ny as u32, nz as u32))
+            })
+        })
+    }
+
+    #[test]
+    fn blocks_agree_with_their_neighbours() {
+        let options = Options {
+            propagation: Propagation::Hard,
+            ..Options::default()
+        };
+        let blocks = Blocks {
+            size: (8, 8, 4),
+            overlap: 2,
+            retries: 4,
+        };
+        let tileset = Arc::new(TileSet::gen(Template::Road));
+        let chunk = gen(20, 14, 6, tileset.clone(), 3, options, blocks).unwrap();
+        assert!(consistent(&chunk, &tileset, (20, 14, 6)));
+    }
+
+    #[test]
+    fn widening_retries_rescue_blocks() {
+        // Without backtracking most blocks of a 3-colouring contradict, and
+        // the colours decided around a block can rule every colour out
+        let options = Options {
+            propagation: Propagation::Hard,
+            attempts: 1,
+            ..Options::default()
+        };
+        let tileset = Arc::new(colours(3));
+        let blocks = Blocks {
+            size: (4, 4, 1),
+            overlap: 1,
+            retries: 0,
+        };
+        let failed = (0..8).filter(|seed| {
+            gen(16, 16, 1, tileset.clone(), *seed, options.clone(), blocks).is_err()
+        });
+        assert!(failed.count() > 0);
+        let blocks = Blocks {
+            retries: 16,
+            ..blocks
+        };
+        for seed in 0..8 {
+            let chunk = gen(16, 16, 1, tileset.clone(), seed, options.clone(), blocks).unwrap();
+            assert!(consistent(&chunk, &tileset, (16, 16, 1)));
+        }
+    }
+}
@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use sol_grid::{Grid, Voxel};
+use toml::Spanned;
+
+use crate::map::{quarter_turns, Direction, FACES, NEIGHBOURHOOD};
+use crate::{model, socket};
+use crate::tile::{self, Orientation, Resolution, Symmetry, Tag, Tile, TileSet, BAN, ROTATIONS};
+
+// A tileset definition file looks like
+//
+//     [size]
+//     width = 3
+//     depth = 3
+//     height = 3
+//
+//     [[tiles]]
+//     name = "road-edge"
+//     model = "road-edge"
+//     tag = "road"
+//     orientation = "edge-west"
+//     symmetry = "T"
+//     tumbles = false
+//     frequency = 1.0
+//
+//     [[rules]]
+//     source = "road-edge"
+//     directions = ["north", "south"]
+//     target = "road-edge"
+//     weight = 0.5
+//     rotate = true
+//
+// A model is either the name of a model::gen function or the path of a
+// MagicaVoxel file relative to the definition, optionally followed by
+// "#<name>" to pick a named model out of a file holding several.
+//
+// With adjacency = "sockets" at the top of the file, the weights start out
+// inferred from the tile voxels (see socket.rs) and rules only override them.
+//
+// With symmetric = "min", "max" or "error" at the top of the file, a rule
+// also sets the weight of its mirror image, e.g. a rule for road-edge north of
+// grass sets grass south of road-edge. When both were set differently the
+// lower or higher weight wins, or the definition is rejected.
+//
+// The symmetry class (X, I, \, T, L or F) defaults to the one implied by the
+// orientation. Tiles are expanded into their variants like TileSet::gen does
+// and can be referred to by their generated names (e.g. "road-edge-east"),
+// the plain name refers to the untransformed tile. Every rule is rotated
+// along with its tiles, so it only needs to be written for one orientation.
+// A rule between invariant tiles thus fans out to all four sides, "dirt east
+// of sand" also putting dirt north, west and south of sand, unless the rule
+// sets rotate = false to only apply in the directions it names. Pairs without
+// a rule are banned.
+//
+// Rules may also name the neighbours across an edge or corner (e.g.
+// "northeast" or "upsouthwest"), which only take part in maps generated with
+// a matching Neighbourhood. Unlike across a face, pairs without a rule in
+// these directions are allowed.
+
+#[derive(Debug)]
+pub enum DefinitionError {
+    Io(io::Error),
+    Invalid { line: usize, message: String },
+}
+
+impl Display for DefinitionError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "{}", error),
+            Self::Invalid { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for DefinitionError {}
+
+#[derive(Deserialize)]
+struct Definition {
+    adjacency: Option<Spanned<String>>,
+    symmetric: Option<Spanned<String>>,
+    size: Size,
+    tiles: Vec<TileDefinition>,
+    #[serde(default)]
+    rules: Vec<RuleDefinition>,
+}
+
+#[derive(Deserialize)]
+struct Size {
+    width: u32,
+    depth: u32,
+    height: u32,
+}
+
+#[derive(Deserialize)]
+struct TileDefinition {
+    name: Spanned<String>,
+    model: Spanned<String>,
+    tag: Spanned<String>,
+    orientation: Option<Spanned<String>>,
+    symmetry: Option<Spanned<String>>,
+    tumbles: Option<bool>,
+    frequency: Option<Spanned<f32>>,
+}
+
+#[derive(Deserialize)]
+struct RuleDefinition {
+    source: Spanned<String>,
+    directions: Vec<Spanned<String>>,
+    target: Spanned<String>,
+    weight: Spanned<f32>,
+    rotate: Option<bool>,
+}
+
+pub fn load(path: &Path) -> Result<TileSet, DefinitionError> {
+    let source = fs::read_to_string(path).map_err(DefinitionError::Io)?;
+    parse(&source, path.parent().unwrap_or(Path::new(".")))
+}
+
+// Parses a definition, resolving .vox paths relative to root
+pub fn parse(source: &str, root: &Path) -> Result<TileSet, DefinitionError> {
+    let invalid = |span: Range<usize>, message: String| DefinitionError::Invalid {
+        line: source[..span.start.min(source.len())].matches('\n').count() + 1,
+        message: message,
+    };
+    let definition: Definition = toml::from_str(source).map_err(|error| {
+        invalid(error.span().unwrap_or(0..0), error.message().to_string())
+    })?;
+    let Size { width, depth, height } = definition.size;
+    if definition.tiles.is_empty() {
+        return Err(invalid(0..0, "no tiles defined".to_string()));
+    }
+
+    // Expand every tile into its variants, remembering which variant ids
+    // belong to which definition
+    let mut tiles = Vec::new();
+    let mut variants: Vec<(Range<u32>, Range<usize>)> = Vec::new();
+    let mut names: HashMap<String, u32> = HashMap::new();
+    for tile in definition.tiles {
+        let tile_symmetry = tile.symmetry;
+        let tile_span = tile.name.span();
+        let name = tile.name.get_ref().clone();
+        if names.contains_key(&name) {
+            return Err(invalid(tile.name.span(), format!("duplicate tile \"{}\"", name)));
+        }
+        let voxels = model(tile.model.get_ref(), root, width, depth, height)
+            .map_err(|message| invalid(tile.model.span(), message))?;
+        let tag = parse_tag(tile.tag.get_ref())
+            .ok_or_else(|| invalid(tile.tag.span(), format!("unknown tag \"{}\"", tile.tag.get_ref())))?;
+        let orientation = match &tile.orientation {
+            Some(orientation) => parse_orientation(orientation.get_ref()).ok_or_else(|| {
+                invalid(orientation.span(), format!("unknown orientation \"{}\"", orientation.get_ref()))
+            })?,
+            None => Orientation::Invariant,
+        };
+        let frequency = match &tile.frequency {
+            // A tile that is never observed can only be backtracked over
+            Some(frequency) if *frequency.get_ref() <= 0.0 => {
+                return Err(invalid(frequency.span(), "frequency must be positive".to_string()))
+            }
+            Some(frequency) => *frequency.get_ref(),
+            None => 1.0,
+        };
+        let mut tile = Tile::new(name.clone(), voxels, tag, orientation, frequency)
+            .with_tumbling(tile.tumbles.unwrap_or(false));
+        if let Some(symmetry) = &tile_symmetry {
+            tile = tile.with_symmetry(parse_symmetry(symmetry.get_ref()).ok_or_else(|| {
+                invalid(symmetry.span(), format!("unknown symmetry \"{}\"", symmetry.get_ref()))
+            })?);
+        }
+        let first_id = tiles.len() as u32;
+        for variant in tile.variants() {
+            names.insert(variant.name().clone(), tiles.len() as u32);
+            tiles.push(variant);
+        }
+        names.insert(name, first_id);
+        variants.push((first_id..tiles.len() as u32, tile_span));
+    }
+
+    // Variant ids of every variant rotated about z, found by comparing
+    // the rotated tile against the other variants of the same definition.
+    // A declared symmetry the model doesn't have leaves some rotations
+    // without a variant
+    let mut rotated: Vec<[u32; 4]> = vec![[0; 4]; tiles.len()];
+    for (ids, span) in variants.iter() {
+        for id in ids.clone() {
+            for (r, rotation) in ROTATIONS.iter().enumerate() {
+                let tile = tiles[id as usize].rotated_z(rotation);
+                rotated[id as usize][r] = ids
+                    .clone()
+                    .find(|other| tiles[*other as usize].same_as(&tile))
+                    .ok_or_else(|| {
+                        let message = format!(
+                            "\"{}\" turned {} degrees is none of its tile's variants, check its symmetry",
+                            tiles[id as usize].name(),
+                            quarter_turns(rotation) * 90
+                        );
+                        invalid(span.clone(), message)
+                    })?;
+            }
+        }
+    }
+
+    let mut updates = HashMap::new();
+    match &definition.adjacency {
+        Some(adjacency) if adjacency.get_ref() == "sockets" => updates = socket::updates(&tiles),
+        Some(adjacency) if adjacency.get_ref() != "rules" => {
+            return Err(invalid(adjacency.span(), format!("unknown adjacency \"{}\"", adjacency.get_ref())))
+        }
+        _ => {
+            for tile_id in 0..tiles.len() as u32 {
+                for direction in FACES {
+                    updates.insert((tile_id, direction), vec![BAN; tiles.len()]);
+                }
+            }
+        }
+    }
+    // Span of the rule behind every weight set by one
+    let mut declared = HashMap::new();
+    for rule in definition.rules {
+        let lookup = |reference: &Spanned<String>| {
+            names.get(reference.get_ref()).copied().ok_or_else(|| {
+                invalid(reference.span(), format!("unknown tile \"{}\"", reference.get_ref()))
+            })
+        };
+        let source = lookup(&rule.source)?;
+        let target = lookup(&rule.target)?;
+        let weight = *rule.weight.get_ref();
+        let rotations = if rule.rotate.unwrap_or(true) { ROTATIONS.len() } else { 1 };
+        if rule.directions.is_empty() {
+            return Err(invalid(rule.source.span(), "rule has no directions".to_string()));
+        }
+        for direction in rule.directions.iter() {
+            let direction = parse_direction(direction.get_ref())
+                .ok_or_else(|| invalid(direction.span(), format!("unknown direction \"{}\"", direction.get_ref())))?;
+            for (r, rotation) in ROTATIONS.iter().enumerate().take(rotations) {
+                let source_id = rotated[source as usize][r];
+                let target_id = rotated[target as usize][r];
+                let direction = direction.rotated_z(rotation);
+                // Directions across an edge or corner start out allowing
+                // every pair
+                for direction in [direction, direction.opposite()] {
+                    for tile_id in 0..tiles.len() as u32 {
+                        updates.entry((tile_id, direction)).or_insert_with(|| vec![0.0; tiles.len()]);
+                    }
+                }
+                updates.get_mut(&(source_id, direction)).unwrap()[target_id as usize] = weight;
+                declared.insert((source_id, direction, target_id), rule.source.span());
+            }
+        }
+    }
+    if let Some(symmetric) = &definition.symmetric {
+        let resolution = match symmetric.get_ref().as_str() {
+            "min" => Resolution::Min,
+            "max" => Resolution::Max,
+            "error" => Resolution::Error,
+            name => return Err(invalid(symmetric.span(), format!("unknown resolution \"{}\"", name))),
+        };
+        let is_declared = |source_id, direction, target_id| {
+            declared.contains_key(&(source_id, direction, target_id))
+        };
+        tile::symmetric(&mut updates, is_declared, resolution).map_err(|conflict| {
+            let span = declared
+                .get(&(conflict.source, conflict.direction, conflict.target))
+                .or_else(|| declared.get(&(conflict.target, conflict.direction.opposite(), conflict.source)))
+                .cloned()
+                .unwrap_or(symmetric.span());
+            let message = format!(
+                "{} with {} {} weighs {}, but the reverse rule weighs {}",
+                tiles[conflict.source as usize].name(),
+                tiles[conflict.target as usize].name(),
+                conflict.direction.relative(),
+                conflict.forward,
+                conflict.backward,
+            );
+            invalid(span, message)
+        })?;
+    }
+    Ok(TileSet::new(tiles, updates))
+}
+
+fn model(
+    name: &str,
+    root: &Path,
+    width: u32,
+    depth: u32,
+    height: u32,
+) -> Result<Grid<Voxel>, String> {
+    let (path, model_name) = match name.split_once('#') {
+        Some((path, model_name)) => (path, Some(model_name)),
+        None => (name, None),
+    };
+    if path.ends_with(".vox") {
+        let path: PathBuf = root.join(path);
+        let voxels = model::load::vox(&path, model_name)
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        if (voxels.width(), voxels.depth(), voxels.height()) != (width, depth, height) {
+            return Err(format!(
+                "model is {}x{}x{}, tiles are {}x{}x{}",
+                voxels.width(),
+                voxels.depth(),
+                voxels.height(),
+                width,
+                depth,
+                height,
+            ));
+        }
+        return Ok(voxels);
+    }
+    match name {
+        "bedrock" => Ok(model::gen::bedrock(width, depth, height)),
+        "dirt" => Ok(model::gen::dirt(width, depth, height)),
+        "grass" => Ok(model::gen::grass(width, depth, height)),
+        "sky" => Ok(model::gen::sky(width, depth, height)),
+        "road-inner" => Ok(model::gen::road_inner(width, depth, height)),
+        "road-edge" => Ok(model::gen::road_edge(width, depth, height)),
+        "road-corner" => Ok(model::gen::road_corner(width, depth, height)),
+        _ => Err(format!("unknown model \"{}\"", name)),
+    }
+}
+
+fn parse_tag(name: &str) -> Option<Tag> {
+    match name {
+        "bedrock" => Some(Tag::Bedrock),
+        "dirt" => Some(Tag::Dirt),
+        "grass" => Some(Tag::Grass),
+        "sky" => Some(Tag::Sky),
+        "road" => Some(Tag::Road),
+        _ => None,
+    }
+}
+
+// Orientations are written as "invariant", "edge-<direction>" or
+// "corner-<direction>", the same way rotated tile names are suffixed
+fn parse_orientation(name: &str) -> Option<Orientation> {
+    if name == "invariant" {
+        Some(Orientation::Invariant)
+    } else if let Some(direction) = name.strip_prefix("edge-") {
+        parse_direction(direction).map(Orientation::Edge)
+    } else if let Some(direction) = name.strip_prefix("corner-") {
+        parse_direction(direction).map(Orientation::Corner)
+    } else {
+        None
+    }
+}
+
+fn parse_symmetry(name: &str) -> Option<Symmetry> {
+    match name {
+        "X" => Some(Symmetry::X),
+        "I" => Some(Symmetry::I),
+        "\\" => Some(Symmetry::Backslash),
+        "T" => Some(Symmetry::T),
+        "L" => Some(Symmetry::L),
+        "F" => Some(Symmetry::F),
+        _ => None,
+    }
+}
+
+fn parse_direction(name: &str) -> Option<Direction> {
+    NEIGHBOURHOOD.into_iter().find(|direction| direction.to_string() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> (usize, String) {
+        match parse(source, Path::new(".")) {
+            Err(DefinitionError::Invalid { line, message }) => (line, message),
+            Err(error) => panic!("unexpected error {}", error),
+            Ok(_) => panic!("parsed invalid definition"),
+        }
+    }
+
+    const SIZE: &str = "[size]\nwidth = 3\ndepth = 3\nheight = 3\n";
+
+    #[test]
+    fn road_definition_matches_template() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tilesets/road.toml");
+        let tileset = load(&path).unwrap();
+        let template = TileSet::gen(crate::tile::Template::Road);
+        assert_eq!(tileset.len(), template.len());
+        for tile_id in 0..template.len() as u32 {
+            let name = template.name(tile_id);
+            let id = tileset.find(name).unwrap();
+            for direction in FACES {
+                for other_id in 0..template.len() as u32 {
+                    let other = tileset.find(template.name(other_id)).unwrap();
+                    assert_eq!(
+                        tileset.update(id, direction)[other as usize],
+                        template.update(tile_id, direction)[other_id as usize],
+                        "{} {} {}",
+                        name,
+                        direction,
+                        template.name(other_id)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rotates_rules_with_their_tiles() {
+        let source = format!(
+            r#"{}
+[[tiles]]
+name = "grass"
+model = "grass"
+tag = "grass"
+
+[[tiles]]
+name = "road-edge"
+model = "road-edge"
+tag = "road"
+orientation = "edge-west"
+
+[[rules]]
+source = "road-edge"
+directions = ["west"]
+target = "grass"
+weight = 0.5
+"#,
+            SIZE
+        );
+        let tileset = parse(&source, Path::new(".")).unwrap();
+        let grass = tileset.find("grass").unwrap() as usize;
+        let north = tileset.find("road-edge-north").unwrap();
+        assert_eq!(tileset.update(north, Direction::North)[grass], 0.5);
+        assert_eq!(tileset.update(north, Direction::West)[grass], BAN);
+        // Faces without a rule stay banned, even between a tile and itself
+        let grass = grass as u32;
+        assert_eq!(tileset.update(grass, Direction::Up)[grass as usize], BAN);
+    }
+
+    #[test]
+    fn unrotated_rules_keep_their_directions() {
+        let definition = |rotate: &str| {
+            format!(
+                r#"{}
+[[tiles]]
+name = "sand"
+model = "grass"
+tag = "grass"
+
+[[tiles]]
+name = "dirt"
+model = "dirt"
+tag = "dirt"
+
+[[rules]]
+source = "sand"
+directions = ["east"]
+target = "dirt"
+weight = 1.0
+{}
+"#,
+                SIZE, rotate
+            )
+        };
+        let sides = |tileset: &TileSet| -> Vec<Direction> {
+            crate::map::SIDES
+                .into_iter()
+                .filter(|direction| tileset.update(0, *direction)[1] > 0.0)
+                .collect()
+        };
+        let rotated = parse(&definition(""), Path::new(".")).unwrap();
+        assert_eq!(sides(&rotated), crate::map::SIDES);
+        let unrotated = parse(&definition("rotate = false"), Path::new(".")).unwrap();
+        assert_eq!(sides(&unrotated), [Direction::East]);
+    }
+
+    #[test]
+    fn reports_the_line_of_the_error() {
+        let tile = "[[tiles]]\nname = \"grass\"\nmodel = \"grass\"\ntag = \"grass\"\n";
+        let rule = |source: &str, direction: &str, target: &str| {
+            format!(
+                "[[rules]]\nsource = \"{}\"\ndirections = [\"{}\"]\ntarget = \"{}\"\nweight = 1.0\n",
+                source, direction, target
+            )
+        };
+        assert_eq!(error(SIZE), (1, "missing field `tiles`".to_string()));
+        assert_eq!(error(&format!("tiles = []\n{}", SIZE)), (1, "no tiles defined".to_string()));
+        assert_eq!(error(&format!("{}{}name =", SIZE, tile)).0, 9);
+        assert_eq!(error(&format!("{}{}{}", SIZE, tile, tile)), (10, "duplicate tile \"grass\"".to_string()));
+        assert_eq!(
+            error(&format!("{}{}", SIZE, tile.replace("tag = \"grass\"", "tag = \"lawn\""))),
+            (8, "unknown tag \"lawn\"".to_string())
+        );
+        assert_eq!(
+            error(&format!("{}{}frequency = 0.0\n", SIZE, tile)),
+            (9, "frequency must be positive".to_string())
+        );
+        assert_eq!(
+            error(&format!("{}{}", SIZE, tile.replace("model = \"grass\"", "model = \"lawn\""))),
+            (7, "unknown model \"lawn\"".to_string())
+        );
+        assert_eq!(
+            error(&format!("{}{}{}", SIZE, tile, rule("grass", "north", "dirt"))),
+            (12, "unknown tile \"dirt\"".to_string())
+        );
+        assert_eq!(
+            error(&format!("{}{}{}", SIZE, tile, rule("grass", "sideways", "grass"))),
+            (11, "unknown direction \"sideways\"".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_symmetries_the_model_lacks() {
+        let source = r#"
+[size]
+width = 3
+depth = 3
+height = 3
+
+[[tiles]]
+name = "road-edge"
+model = "road-edge"
+tag = "road"
+orientation = "edge-west"
+symmetry = "I"
+"#;
+        assert_eq!(
+            error(source),
+            (8, "\"road-edge-west\" turned 180 degrees is none of its tile's variants, check its symmetry".to_string())
+        );
+    }
+}
@@ -0,0 +1,14 @@
+// Struct literals spell out every field, also when a local has its name
+#![allow(clippy::redundant_field_names)]
+
+pub mod analysis;
+pub mod batch;
+pub mod block;
+pub mod definition;
+pub mod math;
+pub mod model;
+pub mod overlap;
+pub mod socket;
+pub mod tile;
+pub mod map;
+pub mod world;
@@ -1,20 +1,159 @@
-mod model;
-mod tile;
-mod map;
+// Struct literals spell out every field, also when a local has its name
+#![allow(clippy::redundant_field_names)]
 
+use std::env;
 use std::fs;
-use std::path::PathBuf;
-use sol_grid::vox;
-use tile::{Template, TileSet};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use sol_grid::{vox, Grid, Voxel};
+use sol_caldera::{analysis, batch, block, definition, model, overlap};
+use sol_caldera::block::Blocks;
+use sol_caldera::map::{Boundaries, Heuristic, Map, Neighbourhood, Options, Propagation};
+use sol_caldera::tile::{Template, TileSet};
+use sol_caldera::world::World;
 
 fn main() {
-    let template = Template::Road;
-    let tileset = TileSet::gen(template);
-    let map = Map::gen(10, 10, 10, tileset);
-    let voxels = map.voxels();
+    if env::args().nth(1).as_deref() == Some("bench") {
+        bench();
+        return;
+    }
+    let seed = env::args()
+        .nth(1)
+        .map(|arg| arg.parse().expect("seed must be an unsigned integer"))
+        .unwrap_or(0);
+    let options = Options {
+        propagation: Propagation::Hard,
+        heuristic: Heuristic::Entropy,
+        max_backtracks: 64,
+        attempts: 8,
+        anchors: Vec::new(),
+        boundaries: Boundaries::default(),
+        // Road corners also constrain the cells diagonal to them
+        neighbourhood: Neighbourhood::Diagonals,
+    };
+    // A .vox second argument is an example for the overlapping model
+    if let Some(path) = env::args().nth(2).filter(|arg| arg.ends_with(".vox")) {
+        let path = Path::new(&path);
+        let example = model::load::vox(path, None).unwrap_or_else(|error| {
+            panic!("{}: {}", path.display(), error)
+        });
+        let voxels = overlap::gen(&example, 3, 32, 32, 16, seed, options)
+            .unwrap_or_else(|error| panic!("{}", error));
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        write(&format!("{}Overlap_{}", name, seed), voxels);
+        return;
+    }
+    // "world" generates the four chunks around the origin of a chunked world
+    if env::args().nth(2).as_deref() == Some("world") {
+        // Seams are generated apart from the cells diagonal to them
+        let options = Options {
+            neighbourhood: Neighbourhood::Faces,
+            ..options
+        };
+        let mut world = World::new(10, 10, 10, TileSet::gen(Template::Road), seed, options)
+            .unwrap_or_else(|error| panic!("{}", error));
+        let coordinates = [(-1, -1), (0, -1), (-1, 0), (0, 0)];
+        for ((x, y), chunk) in coordinates.iter().zip(world.chunks(&coordinates, 0)) {
+            let chunk = chunk.unwrap_or_else(|error| panic!("chunk ({}, {}): {}", x, y, error));
+            write(&format!("{:?}World_{}_{}_{}", Template::Road, seed, x, y), chunk.voxels());
+        }
+        return;
+    }
+    // Otherwise it loads a tileset definition file instead of the built in
+    // template, or infers the template's rules from sockets
+    let (name, tileset) = match env::args().nth(2) {
+        Some(arg) if arg == "sockets" => {
+            (format!("{:?}Sockets", Template::Road), TileSet::infer(Template::Road))
+        }
+        Some(path) => {
+            let path = Path::new(&path);
+            let tileset = definition::load(path).unwrap_or_else(|error| {
+                panic!("{}: {}", path.display(), error)
+            });
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            (name, tileset)
+        }
+        None => (format!("{:?}", Template::Road), TileSet::gen(Template::Road)),
+    };
+    let report = analysis::analyse(&tileset);
+    if !report.is_clean() {
+        eprint!("{}", report);
+    }
+    let generation = Map::gen(10, 10, 10, tileset, seed, options)
+        .unwrap_or_else(|error| panic!("{}", error));
+    println!("generated in {} attempt(s) with seed {}", generation.attempts, generation.seed);
+    write(&format!("{}_{}", name, generation.seed), generation.map.voxels());
+}
+
+fn write(name: &str, voxels: Grid<Voxel>) {
     let bytes = vox::encode(voxels).unwrap();
-    let path = PathBuf::from("models")
-        .join(format!("{:?}", template))
-        .with_extension("vox");
+    let path = PathBuf::from("models").join(name).with_extension("vox");
     fs::write(path, &bytes).unwrap();
 }
+
+// Times hard propagation on the Road tileset, run with
+// `cargo run --release -- bench`
+fn bench() {
+    // Eight maps one after the other and then in parallel
+    let options = Options {
+        propagation: Propagation::Hard,
+        ..Options::default()
+    };
+    let jobs: Vec<batch::Job> = (0..8)
+        .map(|seed| batch::Job {
+            width: 25,
+            depth: 25,
+            height: 12,
+            seed: seed,
+            options: options.clone(),
+        })
+        .collect();
+    let start = Instant::now();
+    batch::gen(TileSet::gen(Template::Road), &jobs, 1);
+    println!("8 x 25x25x12 on 1 thread: {:.3}s", start.elapsed().as_secs_f64());
+    let start = Instant::now();
+    let reports = batch::gen(TileSet::gen(Template::Road), &jobs, 0);
+    println!("8 x 25x25x12 on every core: {:.3}s", start.elapsed().as_secs_f64());
+    for (job, report) in jobs.iter().zip(reports.iter()) {
+        match &report.result {
+            Ok(generation) => println!(
+                "  seed {}: {} attempt(s), {} steps in {:.3}s",
+                job.seed,
+                generation.attempts,
+                generation.steps,
+                report.elapsed.as_secs_f64(),
+            ),
+            Err(error) => println!("  seed {}: {}", job.seed, error.to_string().lines().next().unwrap()),
+        }
+    }
+
+    let sizes = [(10, 10, 5), (25, 25, 12), (50, 50, 25), (100, 100, 50)];
+    for (width, depth, height) in sizes {
+        let options = Options {
+            propagation: Propagation::Hard,
+            ..Options::default()
+        };
+        let tileset = TileSet::gen(Template::Road);
+        let start = Instant::now();
+        let result = Map::gen(width, depth, height, tileset, 0, options.clone());
+        println!(
+            "{}x{}x{}: {} in {:.3}s",
+            width,
+            depth,
+            height,
+            result.map(|_| "ok").unwrap_or("contradiction"),
+            start.elapsed().as_secs_f64(),
+        );
+        let tileset = TileSet::gen(Template::Road);
+        let start = Instant::now();
+        let result = block::gen(width, depth, height, tileset, 0, options, Blocks::default());
+        println!(
+            "{}x{}x{} in blocks: {} in {:.3}s",
+            width,
+            depth,
+            height,
+            result.map(|_| "ok").unwrap_or("contradiction"),
+            start.elapsed().as_secs_f64(),
+        );
+    }
+}
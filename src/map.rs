@@ -1,292 +1,2387 @@
-use std::fs;
-use std::collections::HashSet;
-use std::os::windows::thread;
-use std::path::PathBuf;
-
-use rand::distributions::{Distribution, Uniform, WeightedIndex};
-use rand::rngs::ThreadRng;
-use rand::thread_rng;
-
-
-use sol_grid::{vox, Grid, Rotation, Voxel};
-use crate::tile::{Template, TileSet};
-use crate::math::{add, normalize, entropy};
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum Direction {
-    East,
-    West,
-    North,
-    South,
-    Up,
-    Down,
-    NorthEast,
-    NorthWest,
-    SouthEast,
-    SouthWest,
-}
-
-impl Direction {
-    pub fn rotated_z(&self, rotation: &Rotation) -> Self {
-        match self {
-            Self::East => match rotation {
-                Rotation::R0 => Self::East,
-                Rotation::R90 => Self::North,
-                Rotation::R180 => Self::West,
-                Rotation::R270 => Self::South,
-            },
-            Self::West => match rotation {
-                Rotation::R0 => Self::West,
-                Rotation::R90 => Self::South,
-                Rotation::R180 => Self::East,
-                Rotation::R270 => Self::North,
-            },
-            Self::North => match rotation {
-                Rotation::R0 => Self::North,
-                Rotation::R90 => Self::West,
-                Rotation::R180 => Self::South,
-                Rotation::R270 => Self::East,
-            },
-            Self::South => match rotation {
-                Rotation::R0 => Self::South,
-                Rotation::R90 => Self::East,
-                Rotation::R180 => Self::North,
-                Rotation::R270 => Self::West,
-            },
-            Self::NorthEast => match rotation {
-                Rotation::R0 => Self::NorthEast,
-                Rotation::R90 => Self::NorthWest,
-                Rotation::R180 => Self::SouthWest,
-                Rotation::R270 => Self::SouthEast,
-            },
-            Self::NorthWest => match rotation {
-                Rotation::R0 => Self::NorthWest,
-                Rotation::R90 => Self::SouthWest,
-                Rotation::R180 => Self::SouthEast,
-                Rotation::R270 => Self::NorthEast,
-            },
-            Self::SouthEast => match rotation {
-                Rotation::R0 => Self::SouthEast,
-                Rotation::R90 => Self::NorthEast,
-                Rotation::R180 => Self::NorthWest,
-                Rotation::R270 => Self::SouthWest,
-            },
-            Self::SouthWest => match rotation {
-                Rotation::R0 => Self::SouthWest,
-                Rotation::R90 => Self::SouthEast,
-                Rotation::R180 => Self::NorthEast,
-                Rotation::R270 => Self::NorthWest,
-            },
-            other => *other,
-        }
-    }
-
-    pub fn is_perpendicular(&self, other: Direction) -> bool {
-        match self {
-            Self::East => match other {
-                Self::North | Self::South => true,
-                _ => false,
-            },
-            Self::West => match other {
-                Self::North | Self::South => true,
-                _ => false,
-            },
-            Self::North => match other {
-                Self::East | Self::West => true,
-                _ => false,
-            },
-            Self::South => match other {
-                Self::East | Self::West => true,
-                _ => false,
-            },
-            _ => false,
-        }
-    }
-
-    pub fn is_horizontal(&self) -> bool {
-        match self {
-            Self::East | Self::West | Self::North | Self::South => true,
-            _ => false,
-        }
-    }
-
-    pub fn is_vertical(&self) -> bool {
-        match self {
-            Self::Up | Self::Down => true,
-            _ => false,
-        }
-    }
-}
-
-struct Edge {
-    direction: Direction,
-    wave: usize,
-}
-
-struct Node {
-    pub current_cell_id: usize,
-    pub current_state_name: &'static str,
-    pub current_distance: usize,
-}
-
-impl Node {
-    pub fn new(
-        current_cell_id: usize,
-        current_state_name: &'static str,
-        current_distance: usize,
-    ) -> Node {
-        Node {
-            current_cell_id: current_cell_id,
-            current_state_name: current_state_name,
-            current_distance: current_distance,
-        }
-    }
-}
-
-pub struct Map {
-    width: u32,
-    depth: u32,
-    height: u32,
-    tileset: TileSet,
-    rng: ThreadRng,
-    graph: Vec<Vec<Edge>>,
-    weights: Vec<Vec<f32>>,
-    observations: Vec<Option<u32>>,
-}
-
-impl Map {
-    pub fn new(width: u32, depth: u32, height: u32, tileset: TileSet) -> Map {
-        let rng = thread_rng();
-        let len = width as usize * depth as usize * height as usize;
-        let graph = Vec::with_capacity(len);
-        let weights = Vec::with_capacity(len);
-        let observations = Vec::with_capacity(len);
-        for x in 0..height {
-            for y in 0..depth {
-                for z in 0..width {
-                    graph.push(edges(width, depth, height, x, y, z));
-                    weights.push(vec![0.0; tileset.len()]);
-                    if x == width / 2 && y == depth / 2 && z == 0 {
-                        observations.push(Some(tileset.seed_id()));
-                    } else {
-                        observations.push(None);
-                    }
-                }
-            }
-        }
-        Map {
-            width: width,
-            depth: depth,
-            height: height,
-            tileset: tileset,
-            rng: rng,
-            graph: graph,
-            weights: weights,
-            observations: observations,
-        }
-    }
-
-    pub fn gen(width: u32, depth: u32, height: u32, tileset: TileSet) -> Map {
-        let map = Map::new(width, depth, height, tileset);
-        map.wave_function_collapse();
-        map
-    }
-
-    pub fn wave_function_collapse(&mut self) {
-        let directions = [
-            Direction::East,
-            Direction::West,
-            Direction::North,
-            Direction::South,
-            Direction::Up,
-            Direction::Down,
-        ];
-        let tiles = Vec::from_iter(self.tiles[wave].clone());
-        let observed_tile = tiles.choose(&mut self.rng).unwrap();
-        self.tiles[wave] = HashSet::from([*observed_tile]);
-        while let Some(cell_id) = self.min_entropy_cell_id() {
-            self.observe(cell_id)?;
-            self.propagate(cell_id);
-        }
-        Ok(())
-    }
-
-    fn observe(&mut self, cell_id: usize) -> Result<(), GenerationError> {
-        let distribution = match WeightedIndex::new(&self.cells.weights[cell_id]) {
-            Ok(distribution) => distribution,
-            Err(_) => return Err(GenerationError::Contradiction),
-        };
-        let state_id = distribution.sample(&mut self.rng);
-        self.cells.observations[cell_id] = Some(self.state_names[state_id]);
-        for (id, probability) in self.cells.weights[cell_id].iter_mut().enumerate() {
-            if id == state_id {
-                *probability = 1.0;
-            } else {
-                *probability = 0.0;
-            }
-        }
-        self.cells.entropies[cell_id] = 0.0;
-        Ok(())
-    }
-
-    fn propagate(&mut self, cell_id: usize) {
-        let mut stack = match self.cells.observations[cell_id] {
-            Some(state_name) => vec![Node::new(cell_id, state_name, 0)],
-            None => Vec::new(),
-        };
-        let mut visited = HashSet::new();
-        while let Some(Node {
-            current_cell_id,
-            current_state_name,
-            current_distance,
-        }) = stack.pop()
-        {
-            visited.insert(current_cell_id);
-            for Edge { cell_id, direction } in self.cells.edges[current_cell_id].iter() {
-                if !visited.contains(cell_id) {
-                    let distance = current_distance + 1;
-                    // Update cell if not collapsed
-                    if self.cells.observations[*cell_id].is_none() {
-                        let signal = Signal::new(current_state_name, *direction, distance);
-                        let weights = self
-                            .states
-                            .iter()
-                            .map(|state| (state.update_probability)(signal))
-                            .collect();
-                        add(&mut self.cells.weights[*cell_id], &weights);
-                        normalize(&mut self.cells.weights[*cell_id]);
-                        self.cells.entropies[*cell_id] =
-                            entropy(&self.cells.weights[*cell_id]);
-                    }
-                    // Continue the propagation up to max_distance
-                    if distance < self.max_distance {
-                        stack.push(Node::new(*cell_id, current_state_name, distance));
-                    }
-                }
-            }
-        }
-    }
-
-    pub fn voxels(&self) -> Grid<Voxel> {
-        let mut map = Grid::new(map_width, map_depth, map_height);
-        for (x, y, z, v) in map.enumerate_cells_mut() {
-        }
-        let model_size = 3;
-        let voxels_width = map.width() * model_size;
-        let voxels_depth = map.depth() * model_size;
-        let voxels_height = map.height() * model_size;
-        let mut voxels = Grid::new(voxels_width, voxels_depth, voxels_height);
-        for (mx, my, mz, m) in map.enumerate_cells() {
-            let offset_x = mx * model_size;
-            let offset_y = my * model_size;
-            let offset_z = mz * model_size;
-            for (vx, vy, vz, v) in models[*m as usize].enumerate_cells() {
-                let x = vx + offset_x;
-                let y = vy + offset_y;
-                let z = vz + offset_z;
-                *voxels.get_mut(x, y, z) = *v;
-            }
-        }
-        voxels
-    }
-}
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::sync::Arc;
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use sol_grid::{Grid, Rotation, Voxel};
+use crate::tile::TileSet;
+use crate::math::{add, normalize, entropy, mix};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    East,
+    West,
+    North,
+    South,
+    Up,
+    Down,
+    NorthEast,
+    NorthWest,
+    SouthEast,
+    SouthWest,
+    UpEast,
+    UpWest,
+    UpNorth,
+    UpSouth,
+    DownEast,
+    DownWest,
+    DownNorth,
+    DownSouth,
+    UpNorthEast,
+    UpNorthWest,
+    UpSouthEast,
+    UpSouthWest,
+    DownNorthEast,
+    DownNorthWest,
+    DownSouthEast,
+    DownSouthWest,
+}
+
+// The six neighbours sharing a face with a cell
+pub const FACES: [Direction; 6] = [
+    Direction::East,
+    Direction::West,
+    Direction::North,
+    Direction::South,
+    Direction::Up,
+    Direction::Down,
+];
+
+// The faces around a cell on its own layer
+pub const SIDES: [Direction; 4] = [
+    Direction::East,
+    Direction::West,
+    Direction::North,
+    Direction::South,
+];
+
+// The neighbours sharing only an edge with a cell on its own layer
+pub const DIAGONALS: [Direction; 4] = [
+    Direction::NorthEast,
+    Direction::NorthWest,
+    Direction::SouthEast,
+    Direction::SouthWest,
+];
+
+// Every neighbour of a cell, the faces first, then the cells sharing an edge,
+// the ones on the same layer first, and last the ones sharing only a corner
+pub const NEIGHBOURHOOD: [Direction; 26] = [
+    Direction::East,
+    Direction::West,
+    Direction::North,
+    Direction::South,
+    Direction::Up,
+    Direction::Down,
+    Direction::NorthEast,
+    Direction::NorthWest,
+    Direction::SouthEast,
+    Direction::SouthWest,
+    Direction::UpEast,
+    Direction::UpWest,
+    Direction::UpNorth,
+    Direction::UpSouth,
+    Direction::DownEast,
+    Direction::DownWest,
+    Direction::DownNorth,
+    Direction::DownSouth,
+    Direction::UpNorthEast,
+    Direction::UpNorthWest,
+    Direction::UpSouthEast,
+    Direction::UpSouthWest,
+    Direction::DownNorthEast,
+    Direction::DownNorthWest,
+    Direction::DownSouthEast,
+    Direction::DownSouthWest,
+];
+
+impl Direction {
+    pub fn rotated_z(&self, rotation: &Rotation) -> Self {
+        match self {
+            Self::East => match rotation {
+                Rotation::R0 => Self::East,
+                Rotation::R90 => Self::North,
+                Rotation::R180 => Self::West,
+                Rotation::R270 => Self::South,
+            },
+            Self::West => match rotation {
+                Rotation::R0 => Self::West,
+                Rotation::R90 => Self::South,
+                Rotation::R180 => Self::East,
+                Rotation::R270 => Self::North,
+            },
+            Self::North => match rotation {
+                Rotation::R0 => Self::North,
+                Rotation::R90 => Self::West,
+                Rotation::R180 => Self::South,
+                Rotation::R270 => Self::East,
+            },
+            Self::South => match rotation {
+                Rotation::R0 => Self::South,
+                Rotation::R90 => Self::East,
+                Rotation::R180 => Self::North,
+                Rotation::R270 => Self::West,
+            },
+            Self::NorthEast => match rotation {
+                Rotation::R0 => Self::NorthEast,
+                Rotation::R90 => Self::NorthWest,
+                Rotation::R180 => Self::SouthWest,
+                Rotation::R270 => Self::SouthEast,
+            },
+            Self::NorthWest => match rotation {
+                Rotation::R0 => Self::NorthWest,
+                Rotation::R90 => Self::SouthWest,
+                Rotation::R180 => Self::SouthEast,
+                Rotation::R270 => Self::NorthEast,
+            },
+            Self::SouthEast => match rotation {
+                Rotation::R0 => Self::SouthEast,
+                Rotation::R90 => Self::NorthEast,
+                Rotation::R180 => Self::NorthWest,
+                Rotation::R270 => Self::SouthWest,
+            },
+            Self::SouthWest => match rotation {
+                Rotation::R0 => Self::SouthWest,
+                Rotation::R90 => Self::SouthEast,
+                Rotation::R180 => Self::NorthEast,
+                Rotation::R270 => Self::NorthWest,
+            },
+            Self::Up => Self::Up,
+            Self::Down => Self::Down,
+            other => other.transformed(|(x, y, z)| match rotation {
+                Rotation::R0 => (x, y, z),
+                Rotation::R90 => (-y, x, z),
+                Rotation::R180 => (-x, -y, z),
+                Rotation::R270 => (y, -x, z),
+            }),
+        }
+    }
+
+    // Right handed rotation about the x axis, taking North to Up
+    pub fn rotated_x(&self, rotation: &Rotation) -> Self {
+        let mut direction = *self;
+        for _ in 0..quarter_turns(rotation) {
+            direction = direction.transformed(|(x, y, z)| (x, -z, y));
+        }
+        direction
+    }
+
+    // Right handed rotation about the y axis, taking Up to East
+    pub fn rotated_y(&self, rotation: &Rotation) -> Self {
+        let mut direction = *self;
+        for _ in 0..quarter_turns(rotation) {
+            direction = direction.transformed(|(x, y, z)| (z, y, -x));
+        }
+        direction
+    }
+
+    // Reflection across the plane x = 0, swapping East and West
+    pub fn mirrored_x(&self) -> Self {
+        self.transformed(|(x, y, z)| (-x, y, z))
+    }
+
+    fn transformed(&self, f: impl Fn((i32, i32, i32)) -> (i32, i32, i32)) -> Self {
+        Self::from_offset(f(self.offset()))
+    }
+
+    // Step from a cell to its neighbour in this direction
+    pub fn offset(&self) -> (i32, i32, i32) {
+        match self {
+            Self::East => (1, 0, 0),
+            Self::West => (-1, 0, 0),
+            Self::North => (0, 1, 0),
+            Self::South => (0, -1, 0),
+            Self::Up => (0, 0, 1),
+            Self::Down => (0, 0, -1),
+            Self::NorthEast => (1, 1, 0),
+            Self::NorthWest => (-1, 1, 0),
+            Self::SouthEast => (1, -1, 0),
+            Self::SouthWest => (-1, -1, 0),
+            Self::UpEast => (1, 0, 1),
+            Self::UpWest => (-1, 0, 1),
+            Self::UpNorth => (0, 1, 1),
+            Self::UpSouth => (0, -1, 1),
+            Self::DownEast => (1, 0, -1),
+            Self::DownWest => (-1, 0, -1),
+            Self::DownNorth => (0, 1, -1),
+            Self::DownSouth => (0, -1, -1),
+            Self::UpNorthEast => (1, 1, 1),
+            Self::UpNorthWest => (-1, 1, 1),
+            Self::UpSouthEast => (1, -1, 1),
+            Self::UpSouthWest => (-1, -1, 1),
+            Self::DownNorthEast => (1, 1, -1),
+            Self::DownNorthWest => (-1, 1, -1),
+            Self::DownSouthEast => (1, -1, -1),
+            Self::DownSouthWest => (-1, -1, -1),
+        }
+    }
+
+    fn from_offset(offset: (i32, i32, i32)) -> Self {
+        match offset {
+            (1, 0, 0) => Self::East,
+            (-1, 0, 0) => Self::West,
+            (0, 1, 0) => Self::North,
+            (0, -1, 0) => Self::South,
+            (0, 0, 1) => Self::Up,
+            (0, 0, -1) => Self::Down,
+            (1, 1, 0) => Self::NorthEast,
+            (-1, 1, 0) => Self::NorthWest,
+            (1, -1, 0) => Self::SouthEast,
+            (-1, -1, 0) => Self::SouthWest,
+            (1, 0, 1) => Self::UpEast,
+            (-1, 0, 1) => Self::UpWest,
+            (0, 1, 1) => Self::UpNorth,
+            (0, -1, 1) => Self::UpSouth,
+            (1, 0, -1) => Self::DownEast,
+            (-1, 0, -1) => Self::DownWest,
+            (0, 1, -1) => Self::DownNorth,
+            (0, -1, -1) => Self::DownSouth,
+            (1, 1, 1) => Self::UpNorthEast,
+            (-1, 1, 1) => Self::UpNorthWest,
+            (1, -1, 1) => Self::UpSouthEast,
+            (-1, -1, 1) => Self::UpSouthWest,
+            (1, 1, -1) => Self::DownNorthEast,
+            (-1, 1, -1) => Self::DownNorthWest,
+            (1, -1, -1) => Self::DownSouthEast,
+            (-1, -1, -1) => Self::DownSouthWest,
+            _ => panic!("no direction with offset {:?}", offset),
+        }
+    }
+
+    pub fn opposite(&self) -> Self {
+        self.transformed(|(x, y, z)| (-x, -y, -z))
+    }
+
+    pub fn is_perpendicular(&self, other: Direction) -> bool {
+        let (a, b) = (self.offset(), other.offset());
+        a.0 * b.0 + a.1 * b.1 + a.2 * b.2 == 0
+    }
+
+    // Whether the neighbour in this direction is on the same layer
+    pub fn is_horizontal(&self) -> bool {
+        self.offset().2 == 0
+    }
+
+    // Whether the neighbour in this direction is straight above or below
+    pub fn is_vertical(&self) -> bool {
+        let (x, y, _) = self.offset();
+        x == 0 && y == 0
+    }
+
+    // Whether the neighbour in this direction shares a face with the cell
+    pub fn is_face(&self) -> bool {
+        let (x, y, z) = self.offset();
+        x.abs() + y.abs() + z.abs() == 1
+    }
+
+    // The faces to step through, one after the other, to reach the neighbour
+    // in this direction
+    pub fn faces(&self) -> Vec<Direction> {
+        let (x, y, z) = self.offset();
+        [(x, 0, 0), (0, y, 0), (0, 0, z)]
+            .into_iter()
+            .filter(|offset| *offset != (0, 0, 0))
+            .map(Self::from_offset)
+            .collect()
+    }
+
+    // Where a neighbour in this direction lies, e.g. "above" or "to the east"
+    pub fn relative(&self) -> String {
+        match self {
+            Direction::Up => "above".to_string(),
+            Direction::Down => "below".to_string(),
+            direction => format!("to the {}", direction),
+        }
+    }
+}
+
+// Lowercase name, as written in tileset definitions
+impl Display for Direction {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GenerationError {
+    Contradiction(Contradiction),
+    // The anchors rule each other or the fixed boundaries out before
+    // anything is observed
+    Anchors(Contradiction),
+    // A cell or tile outside of the map or tileset, given by the options or
+    // a caller
+    Invalid(String),
+}
+
+impl Display for GenerationError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Contradiction(contradiction) => write!(f, "{}", contradiction),
+            Self::Anchors(contradiction) => write!(f, "conflicting anchors, {}", contradiction),
+            Self::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for GenerationError {}
+
+// A cell left without any tile, and why each of its tiles was ruled out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contradiction {
+    pub cell: (u32, u32, u32),
+    // Number of steps taken since the last reset, including backtracking
+    pub step: usize,
+    pub eliminations: Vec<Elimination>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Elimination {
+    pub tile_id: u32,
+    pub name: String,
+    pub cause: Cause,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cause {
+    // None of the tiles left in the neighbouring cell in direction allow it,
+    // observation is the neighbour's tile if it was observed
+    Neighbour {
+        cell: (u32, u32, u32),
+        direction: Direction,
+        observation: Option<String>,
+    },
+    // Removed by an anchor, a boundary, a restriction, an observation of the
+    // cell or backtracking
+    Removed,
+}
+
+impl Display for Contradiction {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let (x, y, z) = self.cell;
+        write!(f, "no tile fits at ({}, {}, {}) on step {}", x, y, z, self.step)?;
+        for elimination in self.eliminations.iter() {
+            write!(f, "\n  {}: ", elimination.name)?;
+            match &elimination.cause {
+                Cause::Neighbour { cell: (x, y, z), direction, observation } => {
+                    match observation {
+                        Some(name) => write!(f, "ruled out by {}", name)?,
+                        None => write!(f, "ruled out by the tiles left")?,
+                    }
+                    write!(f, " {} at ({}, {}, {})", direction.relative(), x, y, z)?;
+                }
+                Cause::Removed => write!(f, "removed")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    // Neighbour weights accumulate each observation's tileset updates
+    Soft,
+    // Tiles without a compatible tile in every neighbour are removed,
+    // as in the classic simple tiled model
+    Hard,
+}
+
+// Priority of the cell at (x, y, z) for Heuristic::Custom
+pub type Priority = Arc<dyn Fn(&Map, u32, u32, u32) -> f32 + Send + Sync>;
+
+// Order in which wave_function_collapse picks the cells to observe, the cell
+// with the lowest priority goes first
+#[derive(Clone)]
+pub enum Heuristic {
+    // Fewest choices left by Shannon entropy weighted by tile frequency
+    Entropy,
+    // Lowest cell id first, x then y then z
+    Scanline,
+    // Fewest tiles left
+    RemainingValues,
+    // A random order fixed by the seed
+    Random,
+    // Closest to an anchor first, growing outward, or from the centre of the
+    // bottom layer without anchors
+    Distance,
+    // Priority of the cell at (x, y, z), recomputed whenever its weights change
+    Custom(Priority),
+}
+
+impl Debug for Heuristic {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Entropy => write!(f, "Entropy"),
+            Self::Scanline => write!(f, "Scanline"),
+            Self::RemainingValues => write!(f, "RemainingValues"),
+            Self::Random => write!(f, "Random"),
+            Self::Distance => write!(f, "Distance"),
+            Self::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+// Cell restricted to a set of tiles before collapse
+#[derive(Debug, Clone)]
+pub struct Anchor {
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+    pub tiles: Vec<u32>,
+}
+
+impl Anchor {
+    pub fn tile(x: u32, y: u32, z: u32, tile_id: u32) -> Anchor {
+        Anchor::restrict(x, y, z, vec![tile_id])
+    }
+
+    pub fn restrict(x: u32, y: u32, z: u32, tiles: Vec<u32>) -> Anchor {
+        Anchor {
+            x: x,
+            y: y,
+            z: z,
+            tiles: tiles,
+        }
+    }
+}
+
+// Neighbours of a cell whose tiles constrain each other. Directions the
+// tileset has no rules for are left out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Neighbourhood {
+    // The six cells sharing a face
+    Faces,
+    // The faces and the four cells sharing a vertical edge on the same layer
+    Diagonals,
+    // All 26 cells around the cell
+    Full,
+}
+
+impl Neighbourhood {
+    pub fn directions(&self) -> &'static [Direction] {
+        match self {
+            Self::Faces => &NEIGHBOURHOOD[..6],
+            Self::Diagonals => &NEIGHBOURHOOD[..10],
+            Self::Full => &NEIGHBOURHOOD,
+        }
+    }
+}
+
+// What lies beyond a face of the map
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Boundary {
+    // Nothing, tiles on the face are unconstrained in its direction
+    Open,
+    // The opposite face, so the map tiles seamlessly along that axis. Either
+    // face being periodic wraps the axis
+    Periodic,
+    // A tile every cell on the face must allow next to it
+    Fixed(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Boundaries {
+    pub east: Boundary,
+    pub west: Boundary,
+    pub north: Boundary,
+    pub south: Boundary,
+    pub up: Boundary,
+    pub down: Boundary,
+}
+
+impl Boundaries {
+    pub fn all(boundary: Boundary) -> Boundaries {
+        Boundaries {
+            east: boundary,
+            west: boundary,
+            north: boundary,
+            south: boundary,
+            up: boundary,
+            down: boundary,
+        }
+    }
+
+    pub fn get(&self, direction: Direction) -> Boundary {
+        match direction {
+            Direction::East => self.east,
+            Direction::West => self.west,
+            Direction::North => self.north,
+            Direction::South => self.south,
+            Direction::Up => self.up,
+            Direction::Down => self.down,
+            _ => Boundary::Open,
+        }
+    }
+
+    fn wraps(&self, direction: Direction) -> bool {
+        self.get(direction) == Boundary::Periodic
+            || self.get(direction.opposite()) == Boundary::Periodic
+    }
+}
+
+impl Default for Boundaries {
+    fn default() -> Self {
+        Boundaries::all(Boundary::Open)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub propagation: Propagation,
+    pub heuristic: Heuristic,
+    // Number of observations that can be undone after a contradiction,
+    // 0 disables backtracking
+    pub max_backtracks: usize,
+    // Number of times Map::gen restarts with a derived seed on contradiction
+    pub attempts: u32,
+    // Cells fixed before collapse, anchors on the same cell intersect
+    pub anchors: Vec<Anchor>,
+    pub boundaries: Boundaries,
+    pub neighbourhood: Neighbourhood,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            propagation: Propagation::Soft,
+            heuristic: Heuristic::Entropy,
+            max_backtracks: 0,
+            attempts: 1,
+            anchors: Vec::new(),
+            boundaries: Boundaries::default(),
+            neighbourhood: Neighbourhood::Faces,
+        }
+    }
+}
+
+// Outcome of a single Map::step
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepResult {
+    // The cell at cell was observed as tile_id, and propagation changed the
+    // weights of the unobserved cells in changed
+    Observed {
+        cell: (u32, u32, u32),
+        tile_id: u32,
+        changed: Vec<(u32, u32, u32)>,
+    },
+    // The observation contradicted, the map undid it and the ones after an
+    // earlier decision and observes cell again on the next step
+    Backtracked { cell: (u32, u32, u32) },
+    // Every cell is observed
+    Done,
+}
+
+pub struct Generation {
+    pub map: Map,
+    // Number of runs including the successful one
+    pub attempts: u32,
+    // Seed of the successful run, regenerates the same map with attempts = 1
+    pub seed: u64,
+    // Steps taken over all runs, including backtracking
+    pub steps: usize,
+}
+
+// An observation that can be undone. The weights it and the propagation
+// after it changed are on the trail from start onwards
+struct Decision {
+    cell_id: usize,
+    tile_id: Option<u32>,
+    // Tiles already tried at the cell
+    banned: Vec<u32>,
+    start: usize,
+}
+
+// Upper bound of the seeded noise added to every cell's priority, large
+// enough to break ties between equal priorities but well below any real
+// difference
+const NOISE: f32 = 1e-5;
+
+// Heap entry for next_cell_id, ordered so that BinaryHeap pops the lowest
+// priority first and the lowest cell id among equal ones. Entries aren't
+// removed when a cell's priority changes, a new one is pushed instead and the
+// stale one is skipped when popped
+#[derive(PartialEq)]
+struct Candidate {
+    priority: f32,
+    cell_id: usize,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .priority
+            .total_cmp(&self.priority)
+            .then_with(|| other.cell_id.cmp(&self.cell_id))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn direction_index(directions: &[Direction], direction: Direction) -> usize {
+    directions.iter().position(|d| *d == direction).unwrap()
+}
+
+pub fn quarter_turns(rotation: &Rotation) -> usize {
+    match rotation {
+        Rotation::R0 => 0,
+        Rotation::R90 => 1,
+        Rotation::R180 => 2,
+        Rotation::R270 => 3,
+    }
+}
+
+struct Edge {
+    direction: Direction,
+    cell_id: usize,
+    // Indices of direction and its opposite in Map::directions, the last
+    // index of the support counters
+    index: usize,
+    opposite: usize,
+}
+
+// Edges from the cell at (x, y, z) of a width x depth x height map
+fn edges(
+    (width, depth, height): (u32, u32, u32),
+    boundaries: &Boundaries,
+    directions: &[Direction],
+    (x, y, z): (u32, u32, u32),
+) -> Vec<Edge> {
+    let mut edges = Vec::new();
+    let id = |x: i32, y: i32, z: i32| ((z as u32 * depth + y as u32) * width + x as u32) as usize;
+    // Periodic axes link the cells on opposite faces
+    let fits = |v: i32, size: u32, face: Direction| {
+        (v >= 0 && v < size as i32) || boundaries.wraps(face)
+    };
+    for (index, direction) in directions.iter().enumerate() {
+        let (dx, dy, dz) = direction.offset();
+        let (x, y, z) = (x as i32 + dx, y as i32 + dy, z as i32 + dz);
+        if fits(x, width, Direction::East) && fits(y, depth, Direction::North) && fits(z, height, Direction::Up) {
+            let (x, y, z) = (
+                x.rem_euclid(width as i32),
+                y.rem_euclid(depth as i32),
+                z.rem_euclid(height as i32),
+            );
+            edges.push(Edge {
+                direction: *direction,
+                cell_id: id(x, y, z),
+                index: index,
+                opposite: direction_index(directions, direction.opposite()),
+            });
+        }
+    }
+    edges
+}
+
+struct Node {
+    pub current_cell_id: usize,
+    pub current_tile_id: u32,
+    pub current_distance: usize,
+}
+
+impl Node {
+    pub fn new(
+        current_cell_id: usize,
+        current_tile_id: u32,
+        current_distance: usize,
+    ) -> Node {
+        Node {
+            current_cell_id: current_cell_id,
+            current_tile_id: current_tile_id,
+            current_distance: current_distance,
+        }
+    }
+}
+
+pub struct Map {
+    width: u32,
+    depth: u32,
+    height: u32,
+    max_distance: usize,
+    options: Options,
+    tileset: Arc<TileSet>,
+    rng: StdRng,
+    // Directions of the edges in the graph
+    directions: Vec<Direction>,
+    graph: Vec<Vec<Edge>>,
+    weights: Vec<Vec<f32>>,
+    priorities: Vec<f32>,
+    noise: Vec<f32>,
+    candidates: BinaryHeap<Candidate>,
+    observations: Vec<Option<u32>>,
+    // Number of tiles left with a weight above 0 in every cell
+    remaining: Vec<u32>,
+    // Number of remaining tiles of the neighbour in every direction that
+    // allow each tile of each cell, which can't exceed the tile count
+    supports: Vec<u16>,
+    decisions: VecDeque<Decision>,
+    // Every weight changed since the oldest decision, as (cell, tile,
+    // previous weight)
+    trail: VecDeque<(usize, u32, f32)>,
+    // Entries dropped from the front of the trail along with old decisions
+    trimmed: usize,
+    // Whether the anchors were checked and propagated since the last reset
+    started: bool,
+    // Cell to observe again after backtracking
+    pending: Option<usize>,
+    // Cells whose weights changed during the current step
+    changed: Vec<usize>,
+    steps: usize,
+}
+
+impl Map {
+    pub fn new(
+        width: u32,
+        depth: u32,
+        height: u32,
+        tileset: impl Into<Arc<TileSet>>,
+        seed: u64,
+        options: Options,
+    ) -> Map {
+        let tileset = tileset.into();
+        let directions: Vec<Direction> = options
+            .neighbourhood
+            .directions()
+            .iter()
+            .copied()
+            .filter(|direction| tileset.constrains(*direction) && tileset.constrains(direction.opposite()))
+            .collect();
+        let len = width as usize * depth as usize * height as usize;
+        let mut graph = Vec::with_capacity(len);
+        // Cell ids are laid out x-fastest
+        for z in 0..height {
+            for y in 0..depth {
+                for x in 0..width {
+                    let boundaries = &options.boundaries;
+                    graph.push(edges((width, depth, height), boundaries, &directions, (x, y, z)));
+                }
+            }
+        }
+        let mut map = Map {
+            width: width,
+            depth: depth,
+            height: height,
+            max_distance: 1,
+            options: options,
+            tileset: tileset,
+            rng: StdRng::seed_from_u64(seed),
+            directions: directions,
+            graph: graph,
+            weights: Vec::with_capacity(len),
+            priorities: Vec::with_capacity(len),
+            noise: Vec::with_capacity(len),
+            candidates: BinaryHeap::with_capacity(len),
+            observations: Vec::with_capacity(len),
+            remaining: Vec::with_capacity(len),
+            supports: Vec::new(),
+            decisions: VecDeque::new(),
+            trail: VecDeque::new(),
+            trimmed: 0,
+            started: false,
+            pending: None,
+            changed: Vec::new(),
+            steps: 0,
+        };
+        map.reset(seed);
+        map
+    }
+
+    pub fn gen(
+        width: u32,
+        depth: u32,
+        height: u32,
+        tileset: impl Into<Arc<TileSet>>,
+        seed: u64,
+        options: Options,
+    ) -> Result<Generation, GenerationError> {
+        let mut map = Map::new(width, depth, height, tileset, seed, options);
+        let mut seed = seed;
+        let mut attempts = 1;
+        let mut steps = 0;
+        loop {
+            let result = map.wave_function_collapse();
+            steps += map.steps;
+            match result {
+                Ok(()) => {
+                    return Ok(Generation {
+                        map: map,
+                        attempts: attempts,
+                        seed: seed,
+                        steps: steps,
+                    })
+                }
+                Err(GenerationError::Contradiction(_)) if attempts < map.options.attempts => {
+                    seed = mix(seed);
+                    attempts += 1;
+                    map.reset(seed);
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    pub fn reset(&mut self, seed: u64) {
+        let len = self.graph.len();
+        let tile_count = self.tileset.len();
+        let uniform = vec![1.0 / tile_count as f32; tile_count];
+        self.rng = StdRng::seed_from_u64(seed);
+        self.weights.clear();
+        self.priorities.clear();
+        self.noise.clear();
+        self.observations.clear();
+        self.remaining.clear();
+        self.forget();
+        self.started = false;
+        self.pending = None;
+        self.steps = 0;
+        for _ in 0..len {
+            let noise = self.rng.gen::<f32>() * NOISE;
+            self.noise.push(noise);
+        }
+        for _ in 0..len {
+            self.weights.push(uniform.clone());
+            self.priorities.push(0.0);
+            self.observations.push(None);
+            self.remaining.push(tile_count as u32);
+        }
+        // Anchors and boundaries that don't fit are left for start to report
+        for anchor in self.options.anchors.iter() {
+            let cell_id = match self.cell_id(anchor.x, anchor.y, anchor.z) {
+                Ok(cell_id) => cell_id,
+                Err(_) => continue,
+            };
+            for (tile_id, weight) in self.weights[cell_id].iter_mut().enumerate() {
+                if !anchor.tiles.contains(&(tile_id as u32)) {
+                    *weight = 0.0;
+                }
+            }
+        }
+        // Cells on a fixed face keep only the tiles the boundary tile allows
+        // next to it
+        for cell_id in 0..len {
+            let (x, y, z) = self.position(cell_id);
+            let faces = [
+                (Direction::East, x + 1 == self.width),
+                (Direction::West, x == 0),
+                (Direction::North, y + 1 == self.depth),
+                (Direction::South, y == 0),
+                (Direction::Up, z + 1 == self.height),
+                (Direction::Down, z == 0),
+            ];
+            for (direction, on_face) in faces {
+                if !on_face {
+                    continue;
+                }
+                if let Boundary::Fixed(tile_id) = self.options.boundaries.get(direction) {
+                    if tile_id as usize >= tile_count {
+                        continue;
+                    }
+                    let allowed = self.tileset.allowed(tile_id, direction.opposite());
+                    for (id, weight) in self.weights[cell_id].iter_mut().enumerate() {
+                        if !allowed.contains(&(id as u32)) {
+                            *weight = 0.0;
+                        }
+                    }
+                }
+            }
+        }
+        for cell_id in 0..len {
+            self.count(cell_id);
+            let weights = &mut self.weights[cell_id];
+            let mut remaining = (0..tile_count).filter(|tile_id| weights[*tile_id] > 0.0);
+            match (remaining.next(), remaining.next()) {
+                // Emptied by conflicting anchors or boundaries, reported by
+                // wave_function_collapse
+                (None, _) => (),
+                (Some(tile_id), None) => {
+                    weights[tile_id] = 1.0;
+                    self.observations[cell_id] = Some(tile_id as u32);
+                }
+                (Some(_), Some(_)) => {
+                    normalize(weights);
+                    self.priorities[cell_id] = self.priority(cell_id);
+                }
+            }
+        }
+        self.requeue();
+    }
+
+    pub fn wave_function_collapse(&mut self) -> Result<(), GenerationError> {
+        while self.step()? != StepResult::Done {}
+        Ok(())
+    }
+
+    // Observes a single cell and propagates the observation, or backtracks
+    // if it contradicts
+    pub fn step(&mut self) -> Result<StepResult, GenerationError> {
+        if !self.started {
+            self.start()?;
+        }
+        self.changed.clear();
+        self.steps += 1;
+        let cell_id = match self.pending.take() {
+            Some(cell_id) => cell_id,
+            None => match self.next_cell_id() {
+                Some(cell_id) => {
+                    self.decide(cell_id);
+                    cell_id
+                }
+                None => {
+                    self.forget();
+                    return Ok(StepResult::Done);
+                }
+            },
+        };
+        match self.collapse(cell_id) {
+            Ok(tile_id) => {
+                self.changed.sort_unstable();
+                self.changed.dedup();
+                Ok(StepResult::Observed {
+                    cell: self.position(cell_id),
+                    tile_id: tile_id,
+                    changed: self.changed.iter().map(|cell_id| self.position(*cell_id)).collect(),
+                })
+            }
+            Err(error) => {
+                let cell_id = self.backtrack().ok_or(error)?;
+                self.pending = Some(cell_id);
+                Ok(StepResult::Backtracked {
+                    cell: self.position(cell_id),
+                })
+            }
+        }
+    }
+
+    // Removes every tile but the given ones from the cell at (x, y, z) in the
+    // middle of a run and propagates the removal, returning the other cells
+    // that changed. Decisions made before can't be undone without losing the
+    // new constraint, so backtracking can't go back past it. Soft propagation
+    // only spreads observations, so there the cell's weights are normalized
+    // and no other cell changes until it is observed. A restriction that
+    // contradicts leaves the map as it was
+    pub fn restrict(
+        &mut self,
+        x: u32,
+        y: u32,
+        z: u32,
+        tiles: &[u32],
+    ) -> Result<Vec<(u32, u32, u32)>, GenerationError> {
+        if !self.started {
+            self.start()?;
+        }
+        let cell_id = self.cell_id(x, y, z)?;
+        for tile_id in tiles.iter() {
+            self.check_tile(*tile_id)?;
+        }
+        let weights = &self.weights[cell_id];
+        if !tiles.iter().any(|tile_id| weights[*tile_id as usize] > 0.0) {
+            // The tiles still left would only be removed by the restriction
+            let mut contradiction = self.contradiction(cell_id);
+            for tile_id in (0..weights.len() as u32).filter(|tile_id| weights[*tile_id as usize] > 0.0) {
+                contradiction.eliminations.push(Elimination {
+                    tile_id: tile_id,
+                    name: self.tileset.name(tile_id).clone(),
+                    cause: Cause::Removed,
+                });
+            }
+            contradiction.eliminations.sort_by_key(|elimination| elimination.tile_id);
+            return Err(GenerationError::Contradiction(contradiction));
+        }
+        self.changed.clear();
+        // Recorded as a decision of its own until it has propagated, so that
+        // a contradiction further away can be undone
+        let start = self.trimmed + self.trail.len();
+        self.decisions.push_back(Decision {
+            cell_id: cell_id,
+            tile_id: None,
+            banned: Vec::new(),
+            start: start,
+        });
+        let previous = self.weights[cell_id].clone();
+        let mut removed = Vec::new();
+        for (tile_id, weight) in self.weights[cell_id].iter_mut().enumerate() {
+            if *weight > 0.0 && !tiles.contains(&(tile_id as u32)) {
+                *weight = 0.0;
+                removed.push((cell_id, tile_id as u32));
+            }
+        }
+        self.remaining[cell_id] -= removed.len() as u32;
+        self.record(cell_id, &previous);
+        let propagated = match self.options.propagation {
+            Propagation::Soft => {
+                normalize(&mut self.weights[cell_id]);
+                Ok(())
+            }
+            Propagation::Hard => self.constrain(removed),
+        };
+        if let Err(error) = propagated {
+            self.undo(start);
+            self.decisions.pop_back();
+            return Err(error);
+        }
+        self.forget();
+        self.pending = None;
+        if self.observations[cell_id].is_none() {
+            self.update_priority(cell_id);
+        }
+        self.changed.sort_unstable();
+        self.changed.dedup();
+        Ok(self.changed.iter().map(|cell_id| self.position(*cell_id)).collect())
+    }
+
+    // Checks the anchors and propagates them before the first observation
+    fn start(&mut self) -> Result<(), GenerationError> {
+        self.check_options()?;
+        if let Some(cell_id) = self.broken_anchor() {
+            return Err(self.blame(self.contradiction(cell_id)));
+        }
+        match self.options.propagation {
+            Propagation::Soft => {
+                for cell_id in 0..self.observations.len() {
+                    if self.observations[cell_id].is_some() {
+                        self.signal(cell_id);
+                    }
+                }
+            }
+            // Nothing has been observed yet, so only the anchors and fixed
+            // boundaries can leave a cell without tiles here
+            Propagation::Hard => self.rebuild().map_err(|error| match error {
+                GenerationError::Contradiction(contradiction) => self.blame(contradiction),
+                error => error,
+            })?,
+        }
+        self.started = true;
+        Ok(())
+    }
+
+    // Reports a contradiction found before the first observation as the
+    // anchors' fault, unless the fixed boundaries already contradict without
+    // them
+    fn blame(&self, contradiction: Contradiction) -> GenerationError {
+        if self.options.anchors.is_empty() {
+            return GenerationError::Contradiction(contradiction);
+        }
+        let options = Options {
+            anchors: Vec::new(),
+            ..self.options.clone()
+        };
+        let mut map = Map::new(self.width, self.depth, self.height, self.tileset.clone(), 0, options);
+        match map.start() {
+            Ok(()) => GenerationError::Anchors(contradiction),
+            Err(_) => GenerationError::Contradiction(contradiction),
+        }
+    }
+
+    // Finds an anchored cell without a tile left that is allowed next to some
+    // remaining tile of each of its neighbours. Hard propagation finds the
+    // conflicts further apart
+    fn broken_anchor(&self) -> Option<usize> {
+        self.options.anchors.iter().find_map(|anchor| {
+            let cell_id = self.cell_id(anchor.x, anchor.y, anchor.z).ok()?;
+            let remaining = |cell_id: usize| {
+                (0..self.tileset.len() as u32).filter(move |tile_id| {
+                    self.weights[cell_id][*tile_id as usize] > 0.0
+                })
+            };
+            let holds = remaining(cell_id).next().is_some()
+                && self.graph[cell_id].iter().all(|edge| {
+                    remaining(cell_id).any(|tile_id| self.allows(tile_id, edge))
+                });
+            if holds {
+                None
+            } else {
+                Some(cell_id)
+            }
+        })
+    }
+
+    // Whether a tile has a remaining tile to go next to it across edge
+    fn allows(&self, tile_id: u32, edge: &Edge) -> bool {
+        let allowed = self.tileset.allowed(tile_id, edge.direction);
+        allowed.iter().any(|target_id| self.weights[edge.cell_id][*target_id as usize] > 0.0)
+    }
+
+    // Explains why each tile that could have gone in the cell is gone
+    fn contradiction(&self, cell_id: usize) -> Contradiction {
+        let mut eliminations = Vec::new();
+        for tile_id in 0..self.tileset.len() as u32 {
+            let edge = self.graph[cell_id].iter().find(|edge| {
+                match (self.options.propagation, self.observations[edge.cell_id]) {
+                    // Only observations propagate in soft mode
+                    (Propagation::Soft, None) => false,
+                    _ => !self.allows(tile_id, edge),
+                }
+            });
+            let cause = match edge {
+                Some(edge) => Cause::Neighbour {
+                    cell: self.position(edge.cell_id),
+                    direction: edge.direction,
+                    observation: self.observations[edge.cell_id]
+                        .map(|tile_id| self.tileset.name(tile_id).clone()),
+                },
+                None if self.weights[cell_id][tile_id as usize] > 0.0 => continue,
+                None => Cause::Removed,
+            };
+            eliminations.push(Elimination {
+                tile_id: tile_id,
+                name: self.tileset.name(tile_id).clone(),
+                cause: cause,
+            });
+        }
+        Contradiction {
+            cell: self.position(cell_id),
+            step: self.steps,
+            eliminations: eliminations,
+        }
+    }
+
+    fn collapse(&mut self, cell_id: usize) -> Result<u32, GenerationError> {
+        let candidates: Vec<u32> = (0..self.tileset.len() as u32)
+            .filter(|tile_id| self.weights[cell_id][*tile_id as usize] > 0.0)
+            .collect();
+        let tile_id = self.observe(cell_id)?;
+        if let Some(decision) = self.decisions.back_mut() {
+            decision.tile_id = Some(tile_id);
+        }
+        match self.options.propagation {
+            Propagation::Soft => self.signal(cell_id),
+            Propagation::Hard => self.constrain(
+                candidates
+                    .into_iter()
+                    .filter(|candidate| *candidate != tile_id)
+                    .map(|candidate| (cell_id, candidate))
+                    .collect(),
+            )?,
+        }
+        Ok(tile_id)
+    }
+
+    // Starts recording the changes made by observing the cell, so that the
+    // observation can be undone
+    fn decide(&mut self, cell_id: usize) {
+        if self.options.max_backtracks == 0 {
+            return;
+        }
+        if self.decisions.len() == self.options.max_backtracks {
+            self.decisions.pop_front();
+            let start = match self.decisions.front() {
+                Some(decision) => decision.start,
+                None => self.trimmed + self.trail.len(),
+            };
+            while self.trimmed < start {
+                self.trail.pop_front();
+                self.trimmed += 1;
+            }
+        }
+        self.decisions.push_back(Decision {
+            cell_id: cell_id,
+            tile_id: None,
+            banned: Vec::new(),
+            start: self.trimmed + self.trail.len(),
+        });
+    }
+
+    // Drops every decision, after which nothing can be undone
+    fn forget(&mut self) {
+        self.decisions.clear();
+        self.trail.clear();
+        self.trimmed = 0;
+    }
+
+    // Undoes the most recent decision that still has an untried tile and
+    // returns the cell to observe again, banning every tile already tried there
+    fn backtrack(&mut self) -> Option<usize> {
+        while let Some(decision) = self.decisions.back_mut() {
+            // Observing the cell failed without choosing a tile, e.g. when
+            // only tiles with frequency 0 are left, so retrying is no use
+            let exhausted = match decision.tile_id.take() {
+                Some(tile_id) => {
+                    decision.banned.push(tile_id);
+                    false
+                }
+                None => true,
+            };
+            let (cell_id, start) = (decision.cell_id, decision.start);
+            let banned = decision.banned.clone();
+            self.undo(start);
+            if !exhausted && self.ban(cell_id, &banned) {
+                self.queue(cell_id);
+                return Some(cell_id);
+            }
+            self.undo(start);
+            self.queue(cell_id);
+            self.decisions.pop_back();
+        }
+        self.forget();
+        None
+    }
+
+    // Puts the weights changed since start on the trail back, giving the
+    // tiles that come back their support again
+    fn undo(&mut self, start: usize) {
+        let tile_count = self.tileset.len();
+        let direction_count = self.directions.len();
+        let mut restored = Vec::new();
+        while self.trimmed + self.trail.len() > start {
+            let (cell_id, tile_id, weight) = self.trail.pop_back().unwrap();
+            let current = self.weights[cell_id][tile_id as usize];
+            self.weights[cell_id][tile_id as usize] = weight;
+            if current > 0.0 && weight == 0.0 {
+                self.remaining[cell_id] -= 1;
+            }
+            if current == 0.0 && weight > 0.0 {
+                self.remaining[cell_id] += 1;
+                if self.options.propagation == Propagation::Hard {
+                    for edge in self.graph[cell_id].iter() {
+                        for target_id in self.tileset.allowed(tile_id, edge.direction) {
+                            let index = edge.cell_id * tile_count + *target_id as usize;
+                            self.supports[index * direction_count + edge.opposite] += 1;
+                        }
+                    }
+                }
+            }
+            restored.push(cell_id);
+        }
+        restored.sort_unstable();
+        restored.dedup();
+        for cell_id in restored {
+            if self.observations[cell_id].is_none() {
+                self.queue(cell_id);
+            }
+        }
+    }
+
+    // Removes the tiles already tried at a cell whose decision was undone,
+    // returning whether the cell and its neighbours still have tiles left
+    fn ban(&mut self, cell_id: usize, banned: &[u32]) -> bool {
+        self.observations[cell_id] = None;
+        let previous = self.weights[cell_id].clone();
+        let mut removed = Vec::new();
+        for tile_id in banned.iter() {
+            if self.weights[cell_id][*tile_id as usize] > 0.0 {
+                self.weights[cell_id][*tile_id as usize] = 0.0;
+                removed.push((cell_id, *tile_id));
+            }
+        }
+        // Left as it was, the removals would have to take their support away
+        if removed.len() as u32 == self.remaining[cell_id] {
+            self.weights[cell_id] = previous;
+            return false;
+        }
+        self.remaining[cell_id] -= removed.len() as u32;
+        normalize(&mut self.weights[cell_id]);
+        self.record(cell_id, &previous);
+        match self.options.propagation {
+            Propagation::Soft => true,
+            Propagation::Hard => self.constrain(removed).is_ok(),
+        }
+    }
+
+    // Puts the weights of the cell that differ from previous on the trail
+    fn record(&mut self, cell_id: usize, previous: &[f32]) {
+        if self.decisions.is_empty() {
+            return;
+        }
+        for (tile_id, weight) in previous.iter().enumerate() {
+            if *weight != self.weights[cell_id][tile_id] {
+                self.trail.push_back((cell_id, tile_id as u32, *weight));
+            }
+        }
+    }
+
+    // Recounts the tiles left in the cell after its weights were rewritten
+    fn count(&mut self, cell_id: usize) {
+        let remaining = self.weights[cell_id].iter().filter(|weight| **weight > 0.0);
+        self.remaining[cell_id] = remaining.count() as u32;
+    }
+
+    fn next_cell_id(&mut self) -> Option<usize> {
+        while let Some(Candidate { priority, cell_id }) = self.candidates.pop() {
+            // Compared bitwise so that cells a Custom priority gives NaN,
+            // which never equals itself, still come up
+            let current = self.priorities[cell_id];
+            if self.observations[cell_id].is_none() && priority.to_bits() == current.to_bits() {
+                return Some(cell_id);
+            }
+        }
+        None
+    }
+
+    fn priority(&self, cell_id: usize) -> f32 {
+        let noise = self.noise[cell_id];
+        match &self.options.heuristic {
+            Heuristic::Entropy => {
+                entropy(&self.weights[cell_id], self.tileset.frequencies()) + noise
+            }
+            Heuristic::Scanline => 0.0,
+            Heuristic::RemainingValues => self.remaining[cell_id] as f32 + noise,
+            Heuristic::Random => noise,
+            Heuristic::Distance => {
+                let (x, y, z) = self.position(cell_id);
+                let distance = |(ax, ay, az): (u32, u32, u32)| {
+                    let dx = x as f32 - ax as f32;
+                    let dy = y as f32 - ay as f32;
+                    let dz = z as f32 - az as f32;
+                    (dx * dx + dy * dy + dz * dz).sqrt()
+                };
+                if self.options.anchors.is_empty() {
+                    return distance((self.width / 2, self.depth / 2, 0)) + noise;
+                }
+                let anchors = self.options.anchors.iter();
+                let nearest = anchors
+                    .map(|anchor| distance((anchor.x, anchor.y, anchor.z)))
+                    .fold(f32::INFINITY, f32::min);
+                nearest + noise
+            }
+            Heuristic::Custom(priority) => {
+                let (x, y, z) = self.position(cell_id);
+                priority(self, x, y, z)
+            }
+        }
+    }
+
+    // Recomputes the priority of an unobserved cell and queues it again if
+    // it changed
+    fn update_priority(&mut self, cell_id: usize) {
+        let priority = self.priority(cell_id);
+        if priority == self.priorities[cell_id] {
+            return;
+        }
+        self.priorities[cell_id] = priority;
+        self.candidates.push(Candidate {
+            priority: priority,
+            cell_id: cell_id,
+        });
+    }
+
+    // Recomputes the priority of an unobserved cell and queues it again,
+    // also when the priority is the same as before it was observed
+    fn queue(&mut self, cell_id: usize) {
+        self.priorities[cell_id] = self.priority(cell_id);
+        self.candidates.push(Candidate {
+            priority: self.priorities[cell_id],
+            cell_id: cell_id,
+        });
+    }
+
+    // Replaces every queued entry with one per unobserved cell, after the
+    // weights were reset
+    fn requeue(&mut self) {
+        self.candidates.clear();
+        for (cell_id, priority) in self.priorities.iter().enumerate() {
+            if self.observations[cell_id].is_none() {
+                self.candidates.push(Candidate {
+                    priority: *priority,
+                    cell_id: cell_id,
+                });
+            }
+        }
+    }
+
+    fn observe(&mut self, cell_id: usize) -> Result<u32, GenerationError> {
+        // Tile frequencies act as a prior on top of the propagated weights
+        let weights = self.weights[cell_id]
+            .iter()
+            .zip(self.tileset.frequencies())
+            .map(|(weight, frequency)| weight * frequency);
+        let distribution = match WeightedIndex::new(weights) {
+            Ok(distribution) => distribution,
+            Err(_) => return Err(GenerationError::Contradiction(self.contradiction(cell_id))),
+        };
+        let tile_id = distribution.sample(&mut self.rng);
+        self.observations[cell_id] = Some(tile_id as u32);
+        let previous = self.weights[cell_id].clone();
+        for (id, probability) in self.weights[cell_id].iter_mut().enumerate() {
+            if id == tile_id {
+                *probability = 1.0;
+            } else {
+                *probability = 0.0;
+            }
+        }
+        self.record(cell_id, &previous);
+        self.remaining[cell_id] = 1;
+        self.priorities[cell_id] = 0.0;
+        Ok(tile_id as u32)
+    }
+
+    // Soft propagation, adds the observed tile's update to the weights of
+    // every unobserved cell up to max_distance away
+    fn signal(&mut self, cell_id: usize) {
+        let mut stack = match self.observations[cell_id] {
+            Some(tile_id) => vec![Node::new(cell_id, tile_id, 0)],
+            None => Vec::new(),
+        };
+        let mut visited = HashSet::new();
+        let mut updated = Vec::new();
+        while let Some(Node {
+            current_cell_id,
+            current_tile_id,
+            current_distance,
+        }) = stack.pop()
+        {
+            visited.insert(current_cell_id);
+            for Edge { cell_id, direction, .. } in self.graph[current_cell_id].iter() {
+                if !visited.contains(cell_id) {
+                    let distance = current_distance + 1;
+                    // Update cell if not collapsed
+                    if self.observations[*cell_id].is_none() {
+                        let update = self.tileset.update(current_tile_id, *direction);
+                        let previous = self.weights[*cell_id].clone();
+                        add(&mut self.weights[*cell_id], update);
+                        normalize(&mut self.weights[*cell_id]);
+                        if !self.decisions.is_empty() {
+                            for (tile_id, weight) in previous.iter().enumerate() {
+                                if *weight != self.weights[*cell_id][tile_id] {
+                                    self.trail.push_back((*cell_id, tile_id as u32, *weight));
+                                }
+                            }
+                        }
+                        updated.push(*cell_id);
+                    }
+                    // Continue the propagation up to max_distance
+                    if distance < self.max_distance {
+                        stack.push(Node::new(*cell_id, current_tile_id, distance));
+                    }
+                }
+            }
+        }
+        for cell_id in updated {
+            self.count(cell_id);
+            self.update_priority(cell_id);
+            self.changed.push(cell_id);
+        }
+    }
+
+    // Recounts, for every cell, tile and direction, how many remaining tiles
+    // of the neighbour in that direction allow the tile, then removes the
+    // tiles left without support
+    fn rebuild(&mut self) -> Result<(), GenerationError> {
+        let tile_count = self.tileset.len();
+        let direction_count = self.directions.len();
+        self.supports.clear();
+        self.supports.resize(self.graph.len() * tile_count * direction_count, 0);
+        for (cell_id, edges) in self.graph.iter().enumerate() {
+            for tile_id in 0..tile_count {
+                if self.weights[cell_id][tile_id] == 0.0 {
+                    continue;
+                }
+                for edge in edges.iter() {
+                    for target_id in self.tileset.allowed(tile_id as u32, edge.direction) {
+                        let index = edge.cell_id * tile_count + *target_id as usize;
+                        self.supports[index * direction_count + edge.opposite] += 1;
+                    }
+                }
+            }
+        }
+        let mut stack = Vec::new();
+        for (cell_id, edges) in self.graph.iter().enumerate() {
+            for tile_id in 0..tile_count {
+                let index = (cell_id * tile_count + tile_id) * direction_count;
+                let supported = edges.iter().all(|edge| self.supports[index + edge.index] > 0);
+                if self.weights[cell_id][tile_id] > 0.0 && !supported {
+                    self.weights[cell_id][tile_id] = 0.0;
+                    self.remaining[cell_id] -= 1;
+                    stack.push((cell_id, tile_id as u32));
+                }
+            }
+            if self.remaining[cell_id] == 0 {
+                return Err(GenerationError::Contradiction(self.contradiction(cell_id)));
+            }
+        }
+        for cell_id in 0..self.graph.len() {
+            if self.observations[cell_id].is_none() {
+                self.update_priority(cell_id);
+            }
+        }
+        self.constrain(stack)
+    }
+
+    // Hard propagation, each removed tile takes away its support from the
+    // tiles it allowed in the neighbouring cells, removing those left with
+    // none in turn. After a contradiction the removals made so far still
+    // take their support away, so that undoing them restores the counters
+    fn constrain(&mut self, mut stack: Vec<(usize, u32)>) -> Result<(), GenerationError> {
+        let tile_count = self.tileset.len();
+        let direction_count = self.directions.len();
+        let mut updated = Vec::new();
+        let mut contradiction = None;
+        while let Some((current_cell_id, current_tile_id)) = stack.pop() {
+            for Edge { cell_id, direction, opposite, .. } in self.graph[current_cell_id].iter() {
+                for tile_id in self.tileset.allowed(current_tile_id, *direction) {
+                    let index = (cell_id * tile_count + *tile_id as usize) * direction_count + opposite;
+                    self.supports[index] -= 1;
+                    let weight = self.weights[*cell_id][*tile_id as usize];
+                    if self.supports[index] > 0 || weight == 0.0 || contradiction.is_some() {
+                        continue;
+                    }
+                    self.weights[*cell_id][*tile_id as usize] = 0.0;
+                    self.remaining[*cell_id] -= 1;
+                    if !self.decisions.is_empty() {
+                        self.trail.push_back((*cell_id, *tile_id, weight));
+                    }
+                    if self.remaining[*cell_id] == 0 {
+                        contradiction = Some(*cell_id);
+                    }
+                    if self.observations[*cell_id].is_none() {
+                        updated.push(*cell_id);
+                    }
+                    stack.push((*cell_id, *tile_id));
+                }
+            }
+        }
+        if let Some(cell_id) = contradiction {
+            return Err(GenerationError::Contradiction(self.contradiction(cell_id)));
+        }
+        // Entropies are only recomputed once the removals have settled
+        updated.sort_unstable();
+        updated.dedup();
+        for cell_id in updated {
+            self.update_priority(cell_id);
+            self.changed.push(cell_id);
+        }
+        Ok(())
+    }
+
+    pub fn tileset(&self) -> &TileSet {
+        &self.tileset
+    }
+
+    pub fn observation(&self, x: u32, y: u32, z: u32) -> Result<Option<u32>, GenerationError> {
+        Ok(self.observations[self.cell_id(x, y, z)?])
+    }
+
+    // Remaining weight of every tile at (x, y, z), 0 for removed tiles
+    pub fn weights(&self, x: u32, y: u32, z: u32) -> Result<&Vec<f32>, GenerationError> {
+        Ok(&self.weights[self.cell_id(x, y, z)?])
+    }
+
+    fn cell_id(&self, x: u32, y: u32, z: u32) -> Result<usize, GenerationError> {
+        if x >= self.width || y >= self.depth || z >= self.height {
+            return Err(GenerationError::Invalid(format!(
+                "cell ({}, {}, {}) outside of the {}x{}x{} map",
+                x, y, z, self.width, self.depth, self.height
+            )));
+        }
+        Ok(((z * self.depth + y) * self.width + x) as usize)
+    }
+
+    fn check_tile(&self, tile_id: u32) -> Result<(), GenerationError> {
+        if tile_id as usize >= self.tileset.len() {
+            return Err(GenerationError::Invalid(format!(
+                "tile {} not in the tileset of {} tiles",
+                tile_id,
+                self.tileset.len()
+            )));
+        }
+        Ok(())
+    }
+
+    // Checks that every anchor lies inside the map and every tile the
+    // anchors and fixed boundaries name is in the tileset
+    fn check_options(&self) -> Result<(), GenerationError> {
+        // Support counters are 16 bits wide
+        if self.options.propagation == Propagation::Hard && self.tileset.len() > u16::MAX as usize {
+            return Err(GenerationError::Invalid(format!(
+                "tileset of {} tiles, hard propagation supports at most {}",
+                self.tileset.len(),
+                u16::MAX
+            )));
+        }
+        for anchor in self.options.anchors.iter() {
+            self.cell_id(anchor.x, anchor.y, anchor.z)?;
+            for tile_id in anchor.tiles.iter() {
+                self.check_tile(*tile_id)?;
+            }
+        }
+        for direction in FACES {
+            if let Boundary::Fixed(tile_id) = self.options.boundaries.get(direction) {
+                self.check_tile(tile_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn position(&self, cell_id: usize) -> (u32, u32, u32) {
+        let cell_id = cell_id as u32;
+        (
+            cell_id % self.width,
+            cell_id / self.width % self.depth,
+            cell_id / (self.width * self.depth),
+        )
+    }
+
+    pub fn voxels(&self) -> Grid<Voxel> {
+        render(&self.tileset, self.width, self.depth, self.height, |x, y, z| {
+            self.observations[((z * self.depth + y) * self.width + x) as usize]
+        })
+    }
+}
+
+// Lays out the model of the tile at every cell of a width x depth x height
+// box, leaving cells without a tile empty
+pub fn render(
+    tileset: &TileSet,
+    width: u32,
+    depth: u32,
+    height: u32,
+    tile: impl Fn(u32, u32, u32) -> Option<u32>,
+) -> Grid<Voxel> {
+    let model = tileset.voxels(0);
+    let (model_width, model_depth, model_height) = (model.width(), model.depth(), model.height());
+    let mut voxels = Grid::new(width * model_width, depth * model_depth, height * model_height);
+    for z in 0..height {
+        for y in 0..depth {
+            for x in 0..width {
+                let tile_id = match tile(x, y, z) {
+                    Some(tile_id) => tile_id,
+                    None => continue,
+                };
+                let (offset_x, offset_y, offset_z) =
+                    (x * model_width, y * model_depth, z * model_height);
+                for (vx, vy, vz, v) in tileset.voxels(tile_id).enumerate_cells() {
+                    *voxels.get_mut(vx + offset_x, vy + offset_y, vz + offset_z) = *v;
+                }
+            }
+        }
+    }
+    voxels
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::tile::{Orientation, Tag, Template, Tile, BAN};
+
+    // Observations layer by layer, rows of tile ids in base 36 from south to
+    // north
+    fn layers(map: &Map) -> Vec<String> {
+        let mut layers = Vec::new();
+        for z in 0..map.height {
+            let rows: Vec<String> = (0..map.depth)
+                .map(|y| {
+                    (0..map.width)
+                        .map(|x| map.observation(x, y, z).unwrap().map_or('.', |id| char::from_digit(id, 36).unwrap()))
+                        .collect()
+                })
+                .collect();
+            layers.push(rows.join(" "));
+        }
+        layers
+    }
+
+    fn hard() -> Options {
+        Options {
+            propagation: Propagation::Hard,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn seeded_generation_is_pinned() {
+        let generation = Map::gen(6, 6, 5, TileSet::gen(Template::Road), 7, hard()).unwrap();
+        assert_eq!(
+            layers(&generation.map),
+            [
+                "c11cc2 111211 12111c 121112 cc1111 111111",
+                "c11ccc 112c21 2c111c 1c111c cc1111 211121",
+                "c22ccc 11ccc2 cc112c 2c112c cc1111 c121c2",
+                "cccccc 11cccc cc11cc cc12cc cc2222 c2c1cc",
+                "cccccc 21cccc cc12cc cc2ccc cccccc ccc1cc",
+            ]
+        );
+        let generation = Map::gen(10, 10, 6, TileSet::gen(Template::Road), 11, hard()).unwrap();
+        assert_eq!(
+            layers(&generation.map),
+            [
+                "1111111111 11ab1121c1 1198111111 c111111111 1c11211111 1111121121 1111121121 1111111221 c21c111111 c112111111",
+                "2111111111 12cc11c1c1 12cc111112 c111112121 1c11c11211 21111c11c1 11111c11c1 1111112cc1 cc1c222121 c21c111111",
+                "c111111111 1ccc22c1c1 1ccc11112c c11211c1c1 1c11c11c11 c2112c11c1 11211c11c1 211212ccc2 cc1cccc1c1 cc1c212111",
+                "c221111112 1cccccc1c1 1ccc1111cc c12c11c1c2 1c11c21c11 cc22cc11c2 11c22c11c1 c11c1ccccc cc1cccc1c1 cc2cc1c111",
+                "ccc122222c 1cccccc1c1 2ccc1112cc c1cc11c2cc 1c22cc2c22 cccccc11cc 22cccc12c1 c22c1ccccc cc2cccc1c2 ccccc2c112",
+                "ccc2cccccc 2cccccc1c2 cccc211ccc c1cc11cccc 2ccccccccc cccccc11cc cccccc1cc1 cccc2ccccc ccccccc1cc ccccccc22c",
+            ]
+        );
+    }
+
+    // Tiles that may go next to any tile but themselves, small maps of which
+    // need backtracking under hard propagation
+    pub(crate) fn colours(n: u32) -> TileSet {
+        colours_across(n, &FACES)
+    }
+
+    // Colours that also differ from the neighbours in the given directions
+    fn colours_across(n: u32, directions: &[Direction]) -> TileSet {
+        let names: Vec<String> = (0..n).map(|id| format!("colour-{}", id)).collect();
+        let tiles: Vec<(&str, f32)> = names.iter().map(|name| (name.as_str(), 1.0)).collect();
+        patterns(&tiles, directions, |source, _, target| if source == target { BAN } else { 1.0 })
+    }
+
+    // Tileset of 1x1x1 pattern tiles with the given names and frequencies,
+    // weight(source, direction, target) giving the update of every pair
+    // across the given directions
+    pub(crate) fn patterns(
+        tiles: &[(&str, f32)],
+        directions: &[Direction],
+        weight: impl Fn(u32, Direction, u32) -> f32,
+    ) -> TileSet {
+        let count = tiles.len() as u32;
+        let mut updates = HashMap::new();
+        for source in 0..count {
+            for direction in directions.iter().copied() {
+                let update = (0..count).map(|target| weight(source, direction, target)).collect();
+                updates.insert((source, direction), update);
+            }
+        }
+        let tiles = tiles
+            .iter()
+            .map(|(name, frequency)| {
+                Tile::new(name.to_string(), Grid::new(1, 1, 1), Tag::Pattern, Orientation::Invariant, *frequency)
+            })
+            .collect();
+        TileSet::new(tiles, updates)
+    }
+
+    // Support counters recounted from the current weights, as rebuild does
+    fn recount(map: &Map) -> Vec<u16> {
+        let tile_count = map.tileset.len();
+        let mut supports = vec![0; map.graph.len() * tile_count * map.directions.len()];
+        for (cell_id, edges) in map.graph.iter().enumerate() {
+            for tile_id in (0..tile_count).filter(|tile_id| map.weights[cell_id][*tile_id] > 0.0) {
+                for edge in edges.iter() {
+                    for target_id in map.tileset.allowed(tile_id as u32, edge.direction) {
+                        let index = edge.cell_id * tile_count + *target_id as usize;
+                        supports[index * map.directions.len() + edge.opposite] += 1;
+                    }
+                }
+            }
+        }
+        supports
+    }
+
+    fn remaining(map: &Map) -> Vec<u32> {
+        let remaining = |weights: &Vec<f32>| weights.iter().filter(|weight| **weight > 0.0).count() as u32;
+        map.weights.iter().map(remaining).collect()
+    }
+
+    #[test]
+    fn backtracking_is_pinned() {
+        let tileset = Arc::new(colours(3));
+        let cases = [
+            (1, 12, Heuristic::Random, 1000, 663, 1, "210202120101 101010202012 012102021201 101210210120 020102102012 201020210101 012101021210 121212102021 212121010102 020202121210 212010202021 020121010210"),
+            (1, 12, Heuristic::Random, 4, 261, 3, "101210201020 212021020212 120102102101 012021021020 121210210201 202102101012 010210212120 202101020212 021010201021 210121012102 121012101210 010101012102"),
+            (24, 8, Heuristic::Entropy, 64, 68, 1, "02120121 21201210 02120101 10202020 02010201 20202120 12010212 20121021"),
+            (29, 8, Heuristic::Entropy, 2, 84, 2, "21012021 02120212 10212121 01021012 10202120 02010202 21202120 02120201"),
+        ];
+        for (seed, size, heuristic, max_backtracks, steps, attempts, layer) in cases {
+            let options = Options {
+                heuristic: heuristic,
+                max_backtracks: max_backtracks,
+                attempts: 3,
+                ..hard()
+            };
+            let generation = Map::gen(size, size, 1, tileset.clone(), seed, options).unwrap();
+            assert_eq!(layers(&generation.map), [layer]);
+            assert_eq!((generation.steps, generation.attempts), (steps, attempts));
+        }
+    }
+
+    #[test]
+    fn backtracking_gives_up_on_cells_left_with_unobservable_tiles() {
+        // a can't go next to itself and b has frequency 0, so whichever cell
+        // is observed second is left with only b and can't be observed
+        let tileset = patterns(&[("a", 1.0), ("b", 0.0)], &FACES, |source, _, target| {
+            if (source, target) == (0, 0) {
+                BAN
+            } else {
+                1.0
+            }
+        });
+        let options = Options {
+            max_backtracks: 8,
+            ..hard()
+        };
+        let mut map = Map::new(2, 1, 1, tileset, 0, options);
+        let mut steps = 0;
+        let error = loop {
+            steps += 1;
+            assert!(steps < 10, "backtracked forever");
+            match map.step() {
+                Ok(StepResult::Done) => panic!("observed a tile with frequency 0"),
+                Ok(_) => (),
+                Err(error) => break error,
+            }
+        };
+        assert!(matches!(error, GenerationError::Contradiction(_)));
+    }
+
+    #[test]
+    fn backtracking_restores_supports() {
+        let options = Options {
+            heuristic: Heuristic::Random,
+            max_backtracks: 1000,
+            ..hard()
+        };
+        let mut map = Map::new(12, 12, 1, colours(3), 1, options);
+        let mut backtracks = 0;
+        loop {
+            match map.step().unwrap() {
+                StepResult::Backtracked { .. } => {
+                    backtracks += 1;
+                    assert_eq!(map.supports, recount(&map));
+                    assert_eq!(map.remaining, remaining(&map));
+                }
+                StepResult::Done => break,
+                StepResult::Observed { .. } => (),
+            }
+        }
+        assert!(backtracks > 0);
+    }
+
+    // Steps through a map with the heuristic and checks that every observed
+    // cell came first by key among the cells left, up to the noise
+    fn observes_in_order(heuristic: Heuristic, anchors: Vec<Anchor>, key: impl Fn(&Map, usize) -> f32) {
+        let options = Options {
+            heuristic: heuristic,
+            anchors: anchors,
+            ..hard()
+        };
+        let map = Map::new(6, 6, 5, TileSet::gen(Template::Road), 7, options);
+        observes_map_in_order(map, key);
+    }
+
+    fn observes_map_in_order(mut map: Map, key: impl Fn(&Map, usize) -> f32) {
+        loop {
+            let keys: Vec<f32> = (0..map.observations.len()).map(|cell_id| key(&map, cell_id)).collect();
+            let least = (0..keys.len())
+                .filter(|cell_id| map.observations[*cell_id].is_none())
+                .map(|cell_id| keys[cell_id])
+                .fold(f32::INFINITY, f32::min);
+            match map.step().unwrap() {
+                StepResult::Observed { cell: (x, y, z), .. } => {
+                    let cell_id = ((z * map.depth + y) * map.width + x) as usize;
+                    assert!(keys[cell_id] <= least + NOISE, "{:?} observed out of order", (x, y, z));
+                }
+                StepResult::Done => break,
+                StepResult::Backtracked { .. } => (),
+            }
+        }
+    }
+
+    #[test]
+    fn frequencies_weigh_observations_and_entropy() {
+        let tileset = patterns(&[("rare", 1.0), ("common", 9.0)], &[], |_, _, _| 1.0);
+        let generation = Map::gen(20, 20, 5, tileset, 3, Options::default()).unwrap();
+        let common = generation.map.observations.iter().filter(|observation| **observation == Some(1)).count();
+        let ratio = common as f32 / generation.map.observations.len() as f32;
+        assert!((ratio - 0.9).abs() < 0.03, "{}", ratio);
+
+        let weights = [0.5, 0.5];
+        assert!((entropy(&weights, &[1.0, 1.0]) - 2f32.ln()).abs() < 1e-6);
+        let skewed = -(0.1 * 0.1f32.ln() + 0.9 * 0.9f32.ln());
+        assert!((entropy(&weights, &[1.0, 9.0]) - skewed).abs() < 1e-6);
+    }
+
+    #[test]
+    fn scanline_observes_cells_in_order() {
+        let options = Options {
+            heuristic: Heuristic::Scanline,
+            ..hard()
+        };
+        let mut map = Map::new(6, 6, 5, TileSet::gen(Template::Road), 7, options);
+        let mut observed = Vec::new();
+        while let StepResult::Observed { cell: (x, y, z), .. } = map.step().unwrap() {
+            observed.push((z * 6 + y) * 6 + x);
+        }
+        assert!(observed.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(map.observations.iter().all(|observation| observation.is_some()));
+    }
+
+    #[test]
+    fn entropy_observes_the_least_uncertain_cell() {
+        observes_in_order(Heuristic::Entropy, Vec::new(), |map, cell_id| {
+            entropy(&map.weights[cell_id], map.tileset.frequencies())
+        });
+    }
+
+    #[test]
+    fn remaining_values_observes_the_fewest_tiles() {
+        observes_in_order(Heuristic::RemainingValues, Vec::new(), |map, cell_id| {
+            map.weights[cell_id].iter().filter(|weight| **weight > 0.0).count() as f32
+        });
+    }
+
+    #[test]
+    fn random_follows_the_noise() {
+        // Colours leave neighbours of observed cells with less entropy, which
+        // the random order ignores. The noise is scaled up to stand out from
+        // the tolerance for it
+        let options = Options {
+            heuristic: Heuristic::Random,
+            max_backtracks: 64,
+            ..hard()
+        };
+        let map = Map::new(8, 8, 1, colours(4), 3, options);
+        observes_map_in_order(map, |map, cell_id| map.noise[cell_id] / NOISE);
+    }
+
+    #[test]
+    fn distance_grows_outward() {
+        let distance = |(ax, ay, az): (u32, u32, u32)| {
+            move |map: &Map, cell_id: usize| {
+                let (x, y, z) = map.position(cell_id);
+                let (dx, dy, dz) = (x as f32 - ax as f32, y as f32 - ay as f32, z as f32 - az as f32);
+                (dx * dx + dy * dy + dz * dz).sqrt()
+            }
+        };
+        // From the centre of the bottom layer without anchors
+        observes_in_order(Heuristic::Distance, Vec::new(), distance((3, 3, 0)));
+        // From the anchor, which allows every tile
+        let tiles = (0..TileSet::gen(Template::Road).len() as u32).collect();
+        observes_in_order(Heuristic::Distance, vec![Anchor::restrict(1, 4, 2, tiles)], distance((1, 4, 2)));
+    }
+
+    #[test]
+    fn custom_priority_sees_the_map() {
+        // Highest x first, read back through the map the priority is given
+        let priority: Priority = Arc::new(|map: &Map, x, _, _| (map.width - x) as f32);
+        observes_in_order(Heuristic::Custom(priority), Vec::new(), |map, cell_id| {
+            (map.width - map.position(cell_id).0) as f32
+        });
+    }
+
+    #[test]
+    fn fixed_faces_hold_their_tiles() {
+        let tileset = Arc::new(TileSet::gen(Template::Road));
+        let (sky, bedrock) = (tileset.find("sky").unwrap(), tileset.find("bedrock").unwrap());
+        let options = Options {
+            boundaries: Boundaries {
+                up: Boundary::Fixed(sky),
+                down: Boundary::Fixed(bedrock),
+                ..Boundaries::default()
+            },
+            ..hard()
+        };
+        let map = Map::gen(6, 6, 5, tileset.clone(), 7, options).unwrap().map;
+        for (x, y) in (0..6).flat_map(|x| (0..6).map(move |y| (x, y))) {
+            let top = map.observation(x, y, 4).unwrap().unwrap();
+            assert!(tileset.allowed(sky, Direction::Down).contains(&top));
+            let bottom = map.observation(x, y, 0).unwrap().unwrap();
+            assert!(tileset.allowed(bedrock, Direction::Up).contains(&bottom));
+        }
+    }
+
+    #[test]
+    fn only_blames_anchors_for_their_own_conflicts() {
+        // Colour 0 on both sides leaves the cells of a map two wide only
+        // colour 1, next to itself
+        let boundaries = Boundaries {
+            west: Boundary::Fixed(0),
+            east: Boundary::Fixed(0),
+            ..Boundaries::default()
+        };
+        let tileset = Arc::new(colours(2));
+        let gen = |width: u32, anchor: Anchor| {
+            let options = Options {
+                anchors: vec![anchor],
+                boundaries: boundaries,
+                ..hard()
+            };
+            Map::gen(width, 1, 1, tileset.clone(), 0, options).err()
+        };
+        assert!(matches!(gen(2, Anchor::restrict(0, 0, 0, vec![0, 1])), Some(GenerationError::Contradiction(_))));
+        assert!(matches!(gen(2, Anchor::tile(0, 0, 0, 1)), Some(GenerationError::Contradiction(_))));
+        // Three wide fits 1, 0, 1 until the middle is anchored to 1
+        assert!(gen(3, Anchor::restrict(1, 0, 0, vec![0, 1])).is_none());
+        assert!(matches!(gen(3, Anchor::tile(1, 0, 0, 1)), Some(GenerationError::Anchors(_))));
+    }
+
+    #[test]
+    fn periodic_maps_wrap_around() {
+        let tileset = Arc::new(TileSet::gen(Template::Road));
+        let options = Options {
+            boundaries: Boundaries {
+                up: Boundary::Open,
+                down: Boundary::Open,
+                ..Boundaries::all(Boundary::Periodic)
+            },
+            max_backtracks: 16,
+            attempts: 4,
+            ..hard()
+        };
+        let (width, depth) = (7, 5);
+        let map = Map::gen(width, depth, 4, tileset.clone(), 5, options).unwrap().map;
+        for z in 0..4 {
+            for y in 0..depth {
+                let (last, first) = (map.observation(width - 1, y, z), map.observation(0, y, z));
+                let (last, first) = (last.unwrap().unwrap(), first.unwrap().unwrap());
+                assert!(tileset.allowed(last, Direction::East).contains(&first));
+            }
+            for x in 0..width {
+                let (last, first) = (map.observation(x, depth - 1, z), map.observation(x, 0, z));
+                let (last, first) = (last.unwrap().unwrap(), first.unwrap().unwrap());
+                assert!(tileset.allowed(last, Direction::North).contains(&first));
+            }
+        }
+        // The opposite faces are neighbours in the graph
+        let east = map.graph[width as usize - 1].iter().find(|edge| edge.direction == Direction::East);
+        assert_eq!(east.map(|edge| edge.cell_id), Some(0));
+        assert!(map.graph[0].iter().all(|edge| edge.direction != Direction::Down));
+    }
+
+    // Neighbouring pairs of cells in the directions that the tileset doesn't
+    // allow
+    fn violations(map: &Map, directions: &[Direction]) -> Vec<((u32, u32, u32), Direction)> {
+        let mut violations = Vec::new();
+        for cell_id in 0..map.observations.len() {
+            let (x, y, z) = map.position(cell_id);
+            let tile_id = map.observations[cell_id].unwrap();
+            for direction in directions.iter().copied() {
+                let (dx, dy, dz) = direction.offset();
+                let (nx, ny, nz) = (x as i32 + dx, y as i32 + dy, z as i32 + dz);
+                let outside = |v: i32, size: u32| v < 0 || v >= size as i32;
+                if outside(nx, map.width) || outside(ny, map.depth) || outside(nz, map.height) {
+                    continue;
+                }
+                let neighbour = map.observation(nx as u32, ny as u32, nz as u32).unwrap().unwrap();
+                if !map.tileset.allowed(tile_id, direction).contains(&neighbour) {
+                    violations.push(((x, y, z), direction));
+                }
+            }
+        }
+        violations
+    }
+
+    #[test]
+    fn diagonals_keep_road_corners_whole() {
+        // Road corners only meet across a vertical edge when road covers the
+        // edge on both sides or neither, so no road is pinched to a point
+        let tileset = Arc::new(TileSet::gen(Template::Road));
+        let faces = Options {
+            max_backtracks: 16,
+            attempts: 4,
+            ..hard()
+        };
+        let diagonals = Options {
+            neighbourhood: Neighbourhood::Diagonals,
+            ..faces.clone()
+        };
+        for seed in 0..4 {
+            let map = Map::gen(10, 10, 6, tileset.clone(), seed, diagonals.clone()).unwrap().map;
+            assert_eq!(map.directions.len(), 10);
+            assert_eq!(violations(&map, &NEIGHBOURHOOD[..10]), []);
+        }
+
+        // Two colours that may not meet themselves across a vertical edge,
+        // without rules across the faces. A checkerboard square pinches both
+        // at its centre, which only the diagonals catch
+        let tileset = Arc::new(colours_across(2, &DIAGONALS));
+        let anchors = vec![
+            Anchor::tile(0, 0, 0, 0),
+            Anchor::tile(1, 0, 0, 1),
+            Anchor::tile(0, 1, 0, 1),
+            Anchor::tile(1, 1, 0, 0),
+        ];
+        let anchored = |options: &Options| Options {
+            anchors: anchors.clone(),
+            ..options.clone()
+        };
+        assert!(Map::gen(2, 2, 1, tileset.clone(), 0, anchored(&faces)).is_ok());
+        assert!(matches!(
+            Map::gen(2, 2, 1, tileset.clone(), 0, anchored(&diagonals)),
+            Err(GenerationError::Anchors(_))
+        ));
+        let map = Map::gen(6, 6, 1, tileset, 0, diagonals).unwrap().map;
+        assert_eq!(violations(&map, &DIAGONALS), []);
+    }
+
+    #[test]
+    fn full_neighbourhood_constrains_every_neighbour() {
+        // Eight colours that differ from all 26 neighbours, which only a
+        // 2x2x2 repeating pattern satisfies
+        let options = Options {
+            neighbourhood: Neighbourhood::Full,
+            max_backtracks: 64,
+            attempts: 4,
+            ..hard()
+        };
+        let generation = Map::gen(4, 4, 4, colours_across(8, &NEIGHBOURHOOD), 1, options.clone()).unwrap();
+        assert_eq!(generation.map.directions.len(), 26);
+        assert_eq!(violations(&generation.map, &NEIGHBOURHOOD), []);
+        // Directions without rules are left out of the graph
+        let map = Map::new(4, 4, 4, TileSet::gen(Template::Road), 1, options);
+        assert_eq!(map.directions, NEIGHBOURHOOD[..10]);
+    }
+
+    #[test]
+    fn restrict_reports_the_cells_it_propagated_to() {
+        let tileset = Arc::new(TileSet::gen(Template::Road));
+        let options = Options {
+            max_backtracks: 8,
+            ..hard()
+        };
+        let mut map = Map::new(6, 6, 5, tileset.clone(), 7, options);
+        for _ in 0..5 {
+            map.step().unwrap();
+        }
+        // Grass in a cell that could still be road on the grass layer
+        let grass = tileset.find("grass").unwrap();
+        let cell_id = (0..map.observations.len())
+            .find(|cell_id| {
+                let remaining = map.weights[*cell_id].iter().filter(|weight| **weight > 0.0).count();
+                map.observations[*cell_id].is_none() && remaining > 1 && map.weights[*cell_id][grass as usize] > 0.0
+            })
+            .unwrap();
+        let before = map.weights.clone();
+        let (x, y, z) = map.position(cell_id);
+        let changed = map.restrict(x, y, z, &[grass]).unwrap();
+        let expected: Vec<(u32, u32, u32)> = (0..before.len())
+            .filter(|id| *id != cell_id && map.observations[*id].is_none() && before[*id] != map.weights[*id])
+            .map(|id| map.position(id))
+            .collect();
+        assert!(!changed.is_empty());
+        assert_eq!(changed, expected);
+        assert_eq!(map.supports, recount(&map));
+        assert_eq!(map.remaining, remaining(&map));
+        map.wave_function_collapse().unwrap();
+        assert_eq!(map.observation(x, y, z).unwrap(), Some(grass));
+    }
+
+    #[test]
+    fn rejected_restrictions_leave_the_map_as_it_was() {
+        // Red and blue can't go next to themselves, so on a ring of three
+        // cells the two cells restricted to them leave the third grey
+        let tileset = patterns(&[("red", 1.0), ("blue", 1.0), ("grey", 1.0)], &FACES, |source, _, target| {
+            if source == target && source < 2 {
+                BAN
+            } else {
+                1.0
+            }
+        });
+        let options = Options {
+            boundaries: Boundaries {
+                east: Boundary::Periodic,
+                ..Boundaries::default()
+            },
+            ..hard()
+        };
+        let mut map = Map::new(3, 1, 1, tileset, 0, options);
+        map.restrict(1, 0, 0, &[0, 1]).unwrap();
+        map.restrict(2, 0, 0, &[0, 1]).unwrap();
+        let before = (map.weights.clone(), map.supports.clone(), map.remaining.clone());
+        // Red in the first cell turns both others blue, next to each other
+        match map.restrict(0, 0, 0, &[0]) {
+            Err(GenerationError::Contradiction(contradiction)) => assert_ne!(contradiction.cell, (0, 0, 0)),
+            result => panic!("restriction accepted, {:?}", result),
+        }
+        assert_eq!((map.weights.clone(), map.supports.clone(), map.remaining.clone()), before);
+        // Grey already went from the second cell
+        match map.restrict(1, 0, 0, &[2]) {
+            Err(GenerationError::Contradiction(contradiction)) => {
+                let causes: Vec<(u32, Cause)> = contradiction
+                    .eliminations
+                    .into_iter()
+                    .map(|elimination| (elimination.tile_id, elimination.cause))
+                    .collect();
+                assert_eq!(causes, [(0, Cause::Removed), (1, Cause::Removed), (2, Cause::Removed)]);
+            }
+            result => panic!("restriction accepted, {:?}", result),
+        }
+        assert_eq!((map.weights.clone(), map.supports.clone(), map.remaining.clone()), before);
+        map.wave_function_collapse().unwrap();
+        assert_eq!(map.observation(0, 0, 0).unwrap(), Some(2));
+        assert_eq!(map.supports, recount(&map));
+    }
+
+    #[test]
+    fn soft_restrict_only_normalizes_the_cell() {
+        let tileset = Arc::new(TileSet::gen(Template::Road));
+        let mut map = Map::new(6, 6, 5, tileset.clone(), 7, Options::default());
+        map.step().unwrap();
+        let cell_id = map.observations.iter().position(|observation| observation.is_none()).unwrap();
+        let (x, y, z) = map.position(cell_id);
+        let before = map.weights.clone();
+        let tiles = [0, 1];
+        assert_eq!(map.restrict(x, y, z, &tiles).unwrap(), []);
+        for (id, weights) in before.iter().enumerate() {
+            if id != cell_id {
+                assert_eq!(weights, &map.weights[id]);
+            }
+        }
+        let weights = &map.weights[cell_id];
+        assert!(weights.iter().enumerate().all(|(tile_id, weight)| *weight == 0.0 || tile_id < 2));
+        assert!((weights.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn contradictions_explain_every_tile() {
+        // Two neighbouring cells anchored to the same colour
+        let options = Options {
+            anchors: vec![Anchor::tile(0, 0, 0, 0), Anchor::tile(1, 0, 0, 0)],
+            ..hard()
+        };
+        let error = Map::gen(3, 2, 1, colours(2), 0, options).err().unwrap();
+        let expected = Contradiction {
+            cell: (0, 0, 0),
+            step: 0,
+            eliminations: vec![
+                Elimination {
+                    tile_id: 0,
+                    name: "colour-0".to_string(),
+                    cause: Cause::Neighbour {
+                        cell: (1, 0, 0),
+                        direction: Direction::East,
+                        observation: Some("colour-0".to_string()),
+                    },
+                },
+                Elimination {
+                    tile_id: 1,
+                    name: "colour-1".to_string(),
+                    cause: Cause::Removed,
+                },
+            ],
+        };
+        assert_eq!(error, GenerationError::Anchors(expected));
+        assert_eq!(
+            error.to_string(),
+            "conflicting anchors, no tile fits at (0, 0, 0) on step 0\n  \
+             colour-0: ruled out by colour-0 to the east at (1, 0, 0)\n  \
+             colour-1: removed"
+        );
+
+        // Two colours can't alternate around a ring of three cells, which
+        // the first observation shows
+        let options = Options {
+            boundaries: Boundaries {
+                east: Boundary::Periodic,
+                ..Boundaries::default()
+            },
+            ..hard()
+        };
+        let gen = || -> Result<Generation, Box<dyn std::error::Error>> {
+            Ok(Map::gen(3, 1, 1, colours(2), 0, options.clone())?)
+        };
+        let error = gen().err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "no tile fits at (0, 0, 0) on step 1\n  \
+             colour-0: ruled out by the tiles left to the east at (1, 0, 0)\n  \
+             colour-1: ruled out by colour-1 to the west at (2, 0, 0)"
+        );
+        let contradiction = match error.downcast_ref::<GenerationError>() {
+            Some(GenerationError::Contradiction(contradiction)) => contradiction,
+            _ => panic!("unexpected error {}", error),
+        };
+        assert_eq!((contradiction.cell, contradiction.step), ((0, 0, 0), 1));
+        let causes: Vec<&Cause> = contradiction.eliminations.iter().map(|elimination| &elimination.cause).collect();
+        assert_eq!(
+            causes,
+            [
+                &Cause::Neighbour {
+                    cell: (1, 0, 0),
+                    direction: Direction::East,
+                    observation: None,
+                },
+                &Cause::Neighbour {
+                    cell: (2, 0, 0),
+                    direction: Direction::West,
+                    observation: Some("colour-1".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_cells_and_tiles_outside() {
+        let tileset = Arc::new(colours(3));
+        let invalid = |options: Options| match Map::gen(4, 3, 2, tileset.clone(), 0, options) {
+            Err(GenerationError::Invalid(message)) => message,
+            Err(error) => panic!("unexpected error {}", error),
+            Ok(_) => panic!("generated with invalid options"),
+        };
+        let anchored = |anchor: Anchor| Options {
+            anchors: vec![anchor],
+            ..hard()
+        };
+        assert_eq!(invalid(anchored(Anchor::tile(4, 0, 0, 0))), "cell (4, 0, 0) outside of the 4x3x2 map");
+        assert_eq!(invalid(anchored(Anchor::tile(0, 0, 2, 0))), "cell (0, 0, 2) outside of the 4x3x2 map");
+        assert_eq!(invalid(anchored(Anchor::restrict(0, 0, 0, vec![1, 3]))), "tile 3 not in the tileset of 3 tiles");
+        let options = Options {
+            boundaries: Boundaries {
+                up: Boundary::Fixed(7),
+                ..Boundaries::default()
+            },
+            ..hard()
+        };
+        assert_eq!(invalid(options), "tile 7 not in the tileset of 3 tiles");
+
+        let mut map = Map::new(4, 3, 2, tileset.clone(), 0, hard());
+        assert!(matches!(map.restrict(0, 3, 0, &[0]), Err(GenerationError::Invalid(_))));
+        assert!(matches!(map.restrict(0, 0, 0, &[5]), Err(GenerationError::Invalid(_))));
+        assert!(matches!(map.observation(0, 0, 2), Err(GenerationError::Invalid(_))));
+        assert!(matches!(map.weights(9, 0, 0), Err(GenerationError::Invalid(_))));
+        assert_eq!(map.observation(3, 2, 1).unwrap(), None);
+        assert_eq!(map.weights(3, 2, 1).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn same_seed_same_map() {
+        let tileset = Arc::new(TileSet::gen(Template::Road));
+        let options = Options {
+            max_backtracks: 8,
+            attempts: 4,
+            ..hard()
+        };
+        for seed in 0..4 {
+            let a = Map::gen(8, 8, 6, tileset.clone(), seed, options.clone()).unwrap();
+            let b = Map::gen(8, 8, 6, tileset.clone(), seed, options.clone()).unwrap();
+            assert_eq!(layers(&a.map), layers(&b.map));
+            // The reported seed regenerates the map in one attempt
+            let options = Options {
+                attempts: 1,
+                ..options.clone()
+            };
+            let c = Map::gen(8, 8, 6, tileset.clone(), a.seed, options).unwrap();
+            assert_eq!(layers(&a.map), layers(&c.map));
+        }
+    }
+
+    #[test]
+    fn directions() {
+        for direction in NEIGHBOURHOOD {
+            assert_eq!(Direction::from_offset(direction.offset()), direction);
+            assert_eq!(direction.opposite().opposite(), direction);
+            let faces = direction.faces();
+            let sum = faces.iter().map(|face| face.offset()).fold((0, 0, 0), |a, b| {
+                (a.0 + b.0, a.1 + b.1, a.2 + b.2)
+            });
+            assert_eq!(sum, direction.offset());
+            assert_eq!(direction.is_face(), FACES.contains(&direction));
+            for rotation in [Rotation::R90, Rotation::R180, Rotation::R270] {
+                let inverse = match rotation {
+                    Rotation::R90 => Rotation::R270,
+                    Rotation::R270 => Rotation::R90,
+                    other => other,
+                };
+                assert_eq!(direction.rotated_x(&rotation).rotated_x(&inverse), direction);
+                assert_eq!(direction.rotated_y(&rotation).rotated_y(&inverse), direction);
+                assert_eq!(direction.rotated_z(&rotation).rotated_z(&inverse), direction);
+            }
+        }
+        assert_eq!(Direction::North.rotated_x(&Rotation::R90), Direction::Up);
+        assert_eq!(Direction::Up.rotated_y(&Rotation::R90), Direction::East);
+        assert_eq!(Direction::UpNorthEast.rotated_z(&Rotation::R90), Direction::UpNorthWest);
+        assert!(Direction::East.is_perpendicular(Direction::Up));
+        assert!(!Direction::East.is_perpendicular(Direction::NorthEast));
+        assert!(Direction::SouthWest.is_horizontal() && !Direction::UpEast.is_horizontal());
+        assert!(Direction::Down.is_vertical() && !Direction::DownEast.is_vertical());
+        assert_eq!(Direction::DownSouthWest.to_string(), "downsouthwest");
+    }
+}
@@ -0,0 +1,45 @@
+pub fn add(weights: &mut [f32], update: &[f32]) {
+    for (weight, delta) in weights.iter_mut().zip(update) {
+        *weight += delta;
+    }
+}
+
+pub fn normalize(weights: &mut [f32]) {
+    for weight in weights.iter_mut() {
+        if *weight < 0.0 {
+            *weight = 0.0;
+        }
+    }
+    let sum: f32 = weights.iter().sum();
+    if sum > 0.0 {
+        for weight in weights.iter_mut() {
+            *weight /= sum;
+        }
+    }
+}
+
+// Shannon entropy of the weights scaled by the tile frequencies
+pub fn entropy(weights: &[f32], frequencies: &[f32]) -> f32 {
+    let sum: f32 = weights.iter().zip(frequencies).map(|(w, f)| w * f).sum();
+    if sum <= 0.0 {
+        return 0.0;
+    }
+    -weights
+        .iter()
+        .zip(frequencies)
+        .map(|(weight, frequency)| weight * frequency)
+        .filter(|weight| *weight > 0.0)
+        .map(|weight| {
+            let p = weight / sum;
+            p * p.ln()
+        })
+        .sum::<f32>()
+}
+
+// SplitMix64 finalizer, used to derive independent seeds from a parent seed
+pub fn mix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
@@ -1,10 +1,77 @@
+use sol_grid::{Grid, Rotation, Voxel};
+
+use crate::map::quarter_turns;
+
+pub fn is_empty(voxel: &Voxel) -> bool {
+    *voxel == Voxel::from_rgba(&gen::CLEAR)
+}
+
+// Rotations and reflections matching those of Direction, Grid itself only
+// rotates about z
+
+pub fn rotated_x(voxels: &Grid<Voxel>, rotation: &Rotation) -> Grid<Voxel> {
+    let mut rotated = copied(voxels);
+    for _ in 0..quarter_turns(rotation) {
+        let (depth, height) = (rotated.depth(), rotated.height());
+        rotated = transformed(&rotated, (rotated.width(), height, depth), |x, y, z| {
+            (x, height - 1 - z, y)
+        });
+    }
+    rotated
+}
+
+pub fn rotated_y(voxels: &Grid<Voxel>, rotation: &Rotation) -> Grid<Voxel> {
+    let mut rotated = copied(voxels);
+    for _ in 0..quarter_turns(rotation) {
+        let (width, height) = (rotated.width(), rotated.height());
+        rotated = transformed(&rotated, (height, rotated.depth(), width), |x, y, z| {
+            (z, y, width - 1 - x)
+        });
+    }
+    rotated
+}
+
+pub fn mirrored_x(voxels: &Grid<Voxel>) -> Grid<Voxel> {
+    let width = voxels.width();
+    transformed(voxels, (width, voxels.depth(), voxels.height()), |x, y, z| {
+        (width - 1 - x, y, z)
+    })
+}
+
+fn copied(voxels: &Grid<Voxel>) -> Grid<Voxel> {
+    transformed(voxels, (voxels.width(), voxels.depth(), voxels.height()), |x, y, z| (x, y, z))
+}
+
+// Moves the voxel at (x, y, z) to f(x, y, z) in a grid of the given size
+fn transformed(
+    voxels: &Grid<Voxel>,
+    (width, depth, height): (u32, u32, u32),
+    f: impl Fn(u32, u32, u32) -> (u32, u32, u32),
+) -> Grid<Voxel> {
+    let mut transformed = Grid::new(width, depth, height);
+    for (x, y, z, v) in voxels.enumerate_cells() {
+        let (x, y, z) = f(x, y, z);
+        *transformed.get_mut(x, y, z) = *v;
+    }
+    transformed
+}
+
 pub mod gen {
     use sol_grid::{Grid, Voxel};
 
+    const BLACK: [u8; 4] = [40, 40, 45, 255];
     const BROWN: [u8; 4] = [120, 80, 50, 255];
     const GREEN: [u8; 4] = [90, 120, 20, 255];
     const GREY: [u8; 4] = [108, 108, 127, 255];
-    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+    pub const CLEAR: [u8; 4] = [0, 0, 0, 0];
+
+    pub fn bedrock(width: u32, depth: u32, height: u32) -> Grid<Voxel> {
+        let mut voxels = Grid::new(width, depth, height);
+        for (_, _, _, v) in voxels.enumerate_cells_mut() {
+            *v = Voxel::from_rgba(&BLACK);
+        }
+        voxels
+    }
 
     pub fn dirt(width: u32, depth: u32, height: u32) -> Grid<Voxel> {
         let mut voxels = Grid::new(width, depth, height);
@@ -62,3 +129,372 @@ pub mod gen {
         voxels
     }
 }
+
+pub mod load {
+    use std::collections::HashMap;
+    use std::fmt::{self, Display, Formatter};
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+
+    use sol_grid::{Grid, Voxel};
+
+    #[derive(Debug)]
+    pub enum LoadError {
+        Io(io::Error),
+        Invalid(String),
+    }
+
+    impl Display for LoadError {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            match self {
+                Self::Io(error) => write!(f, "{}", error),
+                Self::Invalid(message) => write!(f, "{}", message),
+            }
+        }
+    }
+
+    impl std::error::Error for LoadError {}
+
+    // Largest model MagicaVoxel saves along each axis
+    const MAX_SIZE: u32 = 256;
+
+    pub struct Model {
+        // Name given to the model's transform node in the MagicaVoxel scene
+        pub name: Option<String>,
+        pub voxels: Grid<Voxel>,
+    }
+
+    // Loads a model from a MagicaVoxel file, either the only one it contains
+    // or the one named name
+    pub fn vox(path: &Path, name: Option<&str>) -> Result<Grid<Voxel>, LoadError> {
+        let bytes = fs::read(path).map_err(LoadError::Io)?;
+        let mut models = models(&bytes)?;
+        let index = match name {
+            Some(name) => models
+                .iter()
+                .position(|model| model.name.as_deref() == Some(name))
+                .ok_or_else(|| LoadError::Invalid(format!("no model named \"{}\"", name)))?,
+            None if models.len() == 1 => 0,
+            None => {
+                return Err(LoadError::Invalid(format!(
+                    "file contains {} models, one must be named",
+                    models.len()
+                )))
+            }
+        };
+        Ok(models.swap_remove(index).voxels)
+    }
+
+    pub fn models(bytes: &[u8]) -> Result<Vec<Model>, LoadError> {
+        let mut reader = Reader { bytes: bytes, offset: 0 };
+        if reader.take(4)? != b"VOX " {
+            return Err(LoadError::Invalid("missing VOX header".to_string()));
+        }
+        reader.i32()?;
+        if reader.take(4)? != b"MAIN" {
+            return Err(LoadError::Invalid("missing MAIN chunk".to_string()));
+        }
+        let content_size = reader.u32()? as usize;
+        reader.u32()?;
+        reader.take(content_size)?;
+
+        let mut sizes = Vec::new();
+        let mut voxels = Vec::new();
+        let mut palette = default_palette();
+        let mut transforms = Vec::new();
+        let mut shapes = HashMap::new();
+        while reader.offset < bytes.len() {
+            let id = reader.take(4)?;
+            let content_size = reader.u32()? as usize;
+            let children_size = reader.u32()? as usize;
+            let mut chunk = Reader { bytes: reader.take(content_size)?, offset: 0 };
+            reader.take(children_size)?;
+            match id {
+                b"SIZE" => {
+                    let size = (chunk.u32()?, chunk.u32()?, chunk.u32()?);
+                    if [size.0, size.1, size.2].iter().any(|len| *len > MAX_SIZE) {
+                        return Err(LoadError::Invalid(format!(
+                            "model size {}x{}x{} larger than {}",
+                            size.0, size.1, size.2, MAX_SIZE
+                        )));
+                    }
+                    sizes.push(size);
+                }
+                b"XYZI" => {
+                    // Not preallocated from the count, which may be corrupt.
+                    // A count past the end of the chunk fails in take
+                    let len = chunk.u32()?;
+                    let mut model = Vec::new();
+                    for _ in 0..len {
+                        let xyzi = chunk.take(4)?;
+                        model.push((xyzi[0] as u32, xyzi[1] as u32, xyzi[2] as u32, xyzi[3]));
+                    }
+                    voxels.push(model);
+                }
+                b"RGBA" => {
+                    // Palette entry i holds the color of index i + 1
+                    for i in 0..255 {
+                        let rgba = chunk.take(4)?;
+                        palette[i + 1] = [rgba[0], rgba[1], rgba[2], rgba[3]];
+                    }
+                }
+                b"nTRN" => {
+                    chunk.i32()?;
+                    let attributes = chunk.dict()?;
+                    let child_id = chunk.i32()?;
+                    if let Some(name) = attributes.get("_name") {
+                        transforms.push((name.clone(), child_id));
+                    }
+                }
+                b"nSHP" => {
+                    let node_id = chunk.i32()?;
+                    chunk.dict()?;
+                    let len = chunk.u32()?;
+                    let mut model_ids = Vec::new();
+                    for _ in 0..len {
+                        model_ids.push(chunk.u32()? as usize);
+                        chunk.dict()?;
+                    }
+                    shapes.insert(node_id, model_ids);
+                }
+                _ => (),
+            }
+        }
+        if sizes.len() != voxels.len() {
+            return Err(LoadError::Invalid("SIZE and XYZI chunks don't pair up".to_string()));
+        }
+
+        let mut names = HashMap::new();
+        for (name, child_id) in transforms {
+            for model_id in shapes.get(&child_id).into_iter().flatten() {
+                names.insert(*model_id, name.clone());
+            }
+        }
+        let mut models = Vec::new();
+        for (model_id, ((width, depth, height), model)) in sizes.into_iter().zip(voxels).enumerate() {
+            let mut grid = Grid::new(width, depth, height);
+            for (_, _, _, v) in grid.enumerate_cells_mut() {
+                *v = Voxel::from_rgba(&palette[0]);
+            }
+            for (x, y, z, i) in model {
+                if x >= width || y >= depth || z >= height {
+                    return Err(LoadError::Invalid(format!(
+                        "voxel ({}, {}, {}) outside of model {}",
+                        x, y, z, model_id
+                    )));
+                }
+                *grid.get_mut(x, y, z) = Voxel::from_rgba(&palette[i as usize]);
+            }
+            models.push(Model {
+                name: names.remove(&model_id),
+                voxels: grid,
+            });
+        }
+        Ok(models)
+    }
+
+    struct Reader<'a> {
+        bytes: &'a [u8],
+        offset: usize,
+    }
+
+    impl<'a> Reader<'a> {
+        fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+            let end = match self.offset.checked_add(len) {
+                Some(end) if end <= self.bytes.len() => end,
+                _ => return Err(LoadError::Invalid("unexpected end of file".to_string())),
+            };
+            let bytes = &self.bytes[self.offset..end];
+            self.offset = end;
+            Ok(bytes)
+        }
+
+        fn i32(&mut self) -> Result<i32, LoadError> {
+            let bytes = self.take(4)?;
+            Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        }
+
+        // Sizes and counts, which a corrupt file may give as negative i32s
+        fn u32(&mut self) -> Result<u32, LoadError> {
+            let bytes = self.take(4)?;
+            Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        }
+
+        fn string(&mut self) -> Result<String, LoadError> {
+            let len = self.u32()? as usize;
+            Ok(String::from_utf8_lossy(self.take(len)?).to_string())
+        }
+
+        fn dict(&mut self) -> Result<HashMap<String, String>, LoadError> {
+            let len = self.u32()?;
+            let mut dict = HashMap::new();
+            for _ in 0..len {
+                let key = self.string()?;
+                let value = self.string()?;
+                dict.insert(key, value);
+            }
+            Ok(dict)
+        }
+    }
+
+    // MagicaVoxel's palette for files without an RGBA chunk: index 0 is
+    // empty, then a 6x6x6 color cube without black followed by red, green,
+    // blue and grey ramps
+    fn default_palette() -> [[u8; 4]; 256] {
+        let levels = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+        let ramp = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+        let mut palette = [[0, 0, 0, 0]; 256];
+        let mut i = 1;
+        for r in levels {
+            for g in levels {
+                for b in levels {
+                    if r == 0 && g == 0 && b == 0 {
+                        continue;
+                    }
+                    palette[i] = [r, g, b, 0xff];
+                    i += 1;
+                }
+            }
+        }
+        for channel in 0..4 {
+            for level in ramp {
+                palette[i] = match channel {
+                    0 => [level, 0, 0, 0xff],
+                    1 => [0, level, 0, 0xff],
+                    2 => [0, 0, level, 0xff],
+                    _ => [level, level, level, 0xff],
+                };
+                i += 1;
+            }
+        }
+        palette
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn chunk(id: &[u8; 4], content: &[u8], children: &[u8]) -> Vec<u8> {
+            let mut bytes = id.to_vec();
+            bytes.extend((content.len() as u32).to_le_bytes());
+            bytes.extend((children.len() as u32).to_le_bytes());
+            bytes.extend(content);
+            bytes.extend(children);
+            bytes
+        }
+
+        fn ints(values: &[i32]) -> Vec<u8> {
+            values.iter().flat_map(|value| value.to_le_bytes()).collect()
+        }
+
+        fn string(value: &str) -> Vec<u8> {
+            let mut bytes = ints(&[value.len() as i32]);
+            bytes.extend(value.as_bytes());
+            bytes
+        }
+
+        fn file(chunks: &[Vec<u8>]) -> Vec<u8> {
+            let mut bytes = b"VOX ".to_vec();
+            bytes.extend(ints(&[150]));
+            bytes.extend(chunk(b"MAIN", &[], &chunks.concat()));
+            bytes
+        }
+
+        fn size(width: i32, depth: i32, height: i32) -> Vec<u8> {
+            chunk(b"SIZE", &ints(&[width, depth, height]), &[])
+        }
+
+        fn xyzi(voxels: &[[u8; 4]]) -> Vec<u8> {
+            let mut content = ints(&[voxels.len() as i32]);
+            content.extend(voxels.concat());
+            chunk(b"XYZI", &content, &[])
+        }
+
+        fn invalid(bytes: &[u8]) -> String {
+            match models(bytes) {
+                Err(LoadError::Invalid(message)) => message,
+                Err(error) => panic!("unexpected error {}", error),
+                Ok(_) => panic!("decoded invalid file"),
+            }
+        }
+
+        #[test]
+        fn decodes_voxels_with_the_default_palette() {
+            let models = models(&file(&[size(2, 1, 3), xyzi(&[[1, 0, 2, 1]])])).unwrap();
+            assert_eq!(models.len(), 1);
+            assert_eq!(models[0].name, None);
+            let voxels = &models[0].voxels;
+            assert_eq!((voxels.width(), voxels.depth(), voxels.height()), (2, 1, 3));
+            for (x, y, z, voxel) in voxels.enumerate_cells() {
+                let rgba = if (x, y, z) == (1, 0, 2) { [0xff, 0xff, 0xff, 0xff] } else { [0, 0, 0, 0] };
+                assert_eq!(*voxel, Voxel::from_rgba(&rgba));
+            }
+        }
+
+        #[test]
+        fn decodes_palette() {
+            let mut palette = vec![0; 255 * 4];
+            palette[4..8].copy_from_slice(&[1, 2, 3, 4]);
+            let bytes = file(&[size(1, 1, 1), xyzi(&[[0, 0, 0, 2]]), chunk(b"RGBA", &palette, &[])]);
+            let models = models(&bytes).unwrap();
+            let (_, _, _, voxel) = models[0].voxels.enumerate_cells().next().unwrap();
+            assert_eq!(*voxel, Voxel::from_rgba(&[1, 2, 3, 4]));
+        }
+
+        #[test]
+        fn names_models_from_transform_nodes() {
+            // Node 1 named road, with node 2 as its child
+            let mut transform = ints(&[1, 1]);
+            transform.extend(string("_name"));
+            transform.extend(string("road"));
+            transform.extend(ints(&[2]));
+            // Node 2 shows model 1, without attributes
+            let shape = ints(&[2, 0, 1, 1, 0]);
+            let bytes = file(&[
+                size(1, 1, 1),
+                xyzi(&[]),
+                size(1, 1, 1),
+                xyzi(&[]),
+                chunk(b"nTRN", &transform, &[]),
+                chunk(b"nSHP", &shape, &[]),
+            ]);
+            let names: Vec<Option<String>> = models(&bytes).unwrap().into_iter().map(|model| model.name).collect();
+            assert_eq!(names, [None, Some("road".to_string())]);
+        }
+
+        #[test]
+        fn rejects_corrupt_files() {
+            assert_eq!(invalid(b"RIFF"), "missing VOX header");
+            assert_eq!(invalid(&file(&[size(1, 1, 1)])), "SIZE and XYZI chunks don't pair up");
+            assert_eq!(
+                invalid(&file(&[size(1, 1, 1), xyzi(&[[1, 0, 0, 1]])])),
+                "voxel (1, 0, 0) outside of model 0"
+            );
+            assert_eq!(invalid(&file(&[size(1, 1, 300)])), "model size 1x1x300 larger than 256");
+            assert_eq!(invalid(&file(&[size(-1, 1, 1)])), "model size 4294967295x1x1 larger than 256");
+            // Truncated in the middle of a chunk
+            let bytes = file(&[size(1, 1, 1), xyzi(&[[0, 0, 0, 1]])]);
+            assert_eq!(invalid(&bytes[..bytes.len() - 2]), "unexpected end of file");
+        }
+
+        #[test]
+        fn rejects_negative_lengths() {
+            // Chunk sizes that would overflow the offset
+            let mut bytes = b"VOX ".to_vec();
+            bytes.extend(ints(&[150]));
+            bytes.extend(b"MAIN");
+            bytes.extend(ints(&[-1, 0]));
+            assert_eq!(invalid(&bytes), "unexpected end of file");
+            let mut bytes = file(&[size(1, 1, 1)]);
+            bytes.extend(b"XYZI");
+            bytes.extend(ints(&[-4, -4]));
+            assert_eq!(invalid(&bytes), "unexpected end of file");
+            // Voxel and string counts
+            let bytes = file(&[size(1, 1, 1), chunk(b"XYZI", &ints(&[-1]), &[])]);
+            assert_eq!(invalid(&bytes), "unexpected end of file");
+            let bytes = file(&[chunk(b"nTRN", &ints(&[1, 1, -1]), &[])]);
+            assert_eq!(invalid(&bytes), "unexpected end of file");
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+use sol_grid::{Grid, Voxel};
+
+use crate::map::{GenerationError, Map, Options, Propagation, FACES};
+use crate::tile::{Orientation, Tag, Tile, TileSet, BAN, ROTATIONS};
+
+// Overlapping model: every n x n x n block of the example, in all four
+// rotations about z, becomes a tile weighted by how often it occurs. Two
+// patterns may be neighbours when they agree on every voxel they share once
+// shifted by one cell, and each cell of the output takes the voxel at the
+// origin of its pattern.
+
+// The tileset and the map keep a weight for every pair of patterns and for
+// every pattern in every cell, so an example with many more patterns takes
+// gigabytes to generate from
+pub const MAX_PATTERNS: usize = 1024;
+
+#[derive(Debug)]
+pub enum OverlapError {
+    // n is 0 or larger than the example along some axis
+    Size { n: u32, example: (u32, u32, u32) },
+    // The example holds more than MAX_PATTERNS distinct patterns
+    Patterns(usize),
+    Generation(GenerationError),
+}
+
+impl Display for OverlapError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Size { n, example: (width, depth, height) } => write!(
+                f,
+                "pattern size {} doesn't fit the {}x{}x{} example",
+                n, width, depth, height
+            ),
+            Self::Patterns(count) => write!(
+                f,
+                "example holds {} patterns, at most {} are supported",
+                count, MAX_PATTERNS
+            ),
+            Self::Generation(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for OverlapError {}
+
+pub fn gen(
+    example: &Grid<Voxel>,
+    n: u32,
+    width: u32,
+    depth: u32,
+    height: u32,
+    seed: u64,
+    options: Options,
+) -> Result<Grid<Voxel>, OverlapError> {
+    let tileset = patterns(example, n)?;
+    let options = Options {
+        propagation: Propagation::Hard,
+        ..options
+    };
+    let generation =
+        Map::gen(width, depth, height, tileset, seed, options).map_err(OverlapError::Generation)?;
+    let mut voxels = Grid::new(width, depth, height);
+    for (x, y, z, v) in voxels.enumerate_cells_mut() {
+        if let Some(tile_id) = generation.map.observation(x, y, z).map_err(OverlapError::Generation)? {
+            *v = *generation.map.tileset().voxels(tile_id).get(0, 0, 0);
+        }
+    }
+    Ok(voxels)
+}
+
+pub fn patterns(example: &Grid<Voxel>, n: u32) -> Result<TileSet, OverlapError> {
+    let size = (example.width(), example.depth(), example.height());
+    if n == 0 || n > size.0 || n > size.1 || n > size.2 {
+        return Err(OverlapError::Size { n: n, example: size });
+    }
+    let mut ids: HashMap<Vec<Voxel>, usize> = HashMap::new();
+    let mut patterns: Vec<Grid<Voxel>> = Vec::new();
+    let mut counts: Vec<u32> = Vec::new();
+    for z in 0..=size.2 - n {
+        for y in 0..=size.1 - n {
+            for x in 0..=size.0 - n {
+                let mut pattern = Grid::new(n, n, n);
+                for (px, py, pz, v) in pattern.enumerate_cells_mut() {
+                    *v = *example.get(x + px, y + py, z + pz);
+                }
+                for rotation in ROTATIONS.iter() {
+                    let rotated = pattern.rotated_z(rotation);
+                    let key: Vec<Voxel> = rotated.enumerate_cells().map(|(_, _, _, v)| *v).collect();
+                    let id = *ids.entry(key).or_insert_with(|| {
+                        patterns.push(rotated);
+                        counts.push(0);
+                        patterns.len() - 1
+                    });
+                    counts[id] += 1;
+                }
+            }
+        }
+    }
+
+    if patterns.len() > MAX_PATTERNS {
+        return Err(OverlapError::Patterns(patterns.len()));
+    }
+
+    let mut updates = HashMap::new();
+    for (source_id, source) in patterns.iter().enumerate() {
+        for direction in FACES {
+            let update = patterns
+                .iter()
+                .map(|target| if agrees(source, target, direction.offset(), n) { 1.0 } else { BAN })
+                .collect();
+            updates.insert((source_id as u32, direction), update);
+        }
+    }
+    let tiles = patterns
+        .into_iter()
+        .zip(counts)
+        .enumerate()
+        .map(|(id, (voxels, count))| {
+            Tile::new(
+                format!("pattern-{}", id),
+                voxels,
+                Tag::Pattern,
+                Orientation::Invariant,
+                count as f32,
+            )
+        })
+        .collect();
+    Ok(TileSet::new(tiles, updates))
+}
+
+// Whether target, shifted by offset relative to source, matches source on
+// the voxels they overlap
+fn agrees(source: &Grid<Voxel>, target: &Grid<Voxel>, offset: (i32, i32, i32), n: u32) -> bool {
+    let (dx, dy, dz) = offset;
+    let n = n as i32;
+    for z in 0.max(dz)..n.min(n + dz) {
+        for y in 0.max(dy)..n.min(n + dy) {
+            for x in 0.max(dx)..n.min(n + dx) {
+                let s = source.get(x as u32, y as u32, z as u32);
+                let t = target.get((x - dx) as u32, (y - dy) as u32, (z - dz) as u32);
+                if s != t {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example(width: u32, depth: u32, height: u32) -> Grid<Voxel> {
+        let mut example = Grid::new(width, depth, height);
+        for (x, y, z, v) in example.enumerate_cells_mut() {
+            *v = Voxel::from_rgba(&[(x % 2) as u8, (y % 2) as u8, (z % 2) as u8, 0xff]);
+        }
+        example
+    }
+
+    #[test]
+    fn rejects_patterns_that_dont_fit() {
+        let example = example(4, 3, 2);
+        for n in [0, 3, 5] {
+            match patterns(&example, n) {
+                Err(OverlapError::Size { n: size, example }) => assert_eq!((size, example), (n, (4, 3, 2))),
+                _ => panic!("pattern size {} accepted", n),
+            }
+        }
+        assert!(patterns(&example, 2).is_ok());
+        assert!(matches!(
+            gen(&example, 3, 4, 4, 4, 0, Options::default()),
+            Err(OverlapError::Size { .. })
+        ));
+    }
+
+    #[test]
+    fn counts_every_rotation_of_every_block() {
+        let tileset = patterns(&example(3, 3, 2), 2).unwrap();
+        // 4 blocks of a checkered example, each in 4 rotations
+        let total: f32 = tileset.frequencies().iter().sum();
+        assert_eq!(total, 16.0);
+    }
+
+    #[test]
+    fn rejects_examples_with_too_many_patterns() {
+        // Every voxel a different colour, so every 1x1x1 block differs
+        let mut example = Grid::new(12, 12, 12);
+        for (x, y, z, v) in example.enumerate_cells_mut() {
+            *v = Voxel::from_rgba(&[x as u8, y as u8, z as u8, 0xff]);
+        }
+        assert!(matches!(patterns(&example, 1), Err(OverlapError::Patterns(1728))));
+    }
+
+    #[test]
+    fn output_blocks_are_patterns_of_the_example() {
+        let example = example(4, 4, 4);
+        let n = 2;
+        let tileset = patterns(&example, n).unwrap();
+        let key = |voxels: &Grid<Voxel>, (x, y, z): (u32, u32, u32)| -> Vec<Voxel> {
+            let mut block = Grid::new(n, n, n);
+            for (px, py, pz, v) in block.enumerate_cells_mut() {
+                *v = *voxels.get(x + px, y + py, z + pz);
+            }
+            block.enumerate_cells().map(|(_, _, _, v)| *v).collect()
+        };
+        let known: Vec<Vec<Voxel>> = (0..tileset.len() as u32)
+            .map(|tile_id| key(tileset.voxels(tile_id), (0, 0, 0)))
+            .collect();
+        let options = Options {
+            max_backtracks: 16,
+            attempts: 4,
+            ..Options::default()
+        };
+        let (width, depth, height) = (7, 6, 5);
+        let voxels = gen(&example, n, width, depth, height, 3, options).unwrap();
+        for z in 0..=height - n {
+            for y in 0..=depth - n {
+                for x in 0..=width - n {
+                    assert!(known.contains(&key(&voxels, (x, y, z))), "block at ({}, {}, {})", x, y, z);
+                }
+            }
+        }
+    }
+}
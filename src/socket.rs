@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use sol_grid::{Grid, Voxel};
+
+use crate::map::{Direction, SIDES};
+use crate::model;
+use crate::tile::{Tile, BAN};
+
+// Adjacency inferred from the voxels on the faces of each tile. A side face
+// is read from outside the tile with u running to the left of the outward
+// direction, so two touching side faces connect when one is the mirror image
+// of the other, colours included, and surfaces continue across the seam. Top
+// and bottom faces are read along x and y and connect when every solid voxel
+// on the bottom of the upper tile rests on a solid voxel of the lower one,
+// whatever their colours, as layers of different material stack.
+
+// Interned face profile and its mirror image, the two are equal for
+// symmetric faces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Socket {
+    pub id: u32,
+    pub flipped: u32,
+}
+
+impl Socket {
+    pub fn connects(&self, other: &Socket) -> bool {
+        self.id == other.flipped
+    }
+}
+
+struct Sockets {
+    ids: HashMap<Vec<Option<Voxel>>, u32>,
+}
+
+impl Sockets {
+    fn socket(&mut self, profile: Vec<Option<Voxel>>, width: usize) -> Socket {
+        let flipped = profile
+            .chunks(width)
+            .flat_map(|row| row.iter().rev().copied())
+            .collect();
+        Socket {
+            id: self.intern(profile),
+            flipped: self.intern(flipped),
+        }
+    }
+
+    fn intern(&mut self, profile: Vec<Option<Voxel>>) -> u32 {
+        let len = self.ids.len() as u32;
+        *self.ids.entry(profile).or_insert(len)
+    }
+}
+
+pub fn updates(tiles: &[Tile]) -> HashMap<(u32, Direction), Vec<f32>> {
+    let mut sockets = Sockets { ids: HashMap::new() };
+    let sides: Vec<HashMap<Direction, Socket>> = tiles
+        .iter()
+        .map(|tile| {
+            SIDES
+                .iter()
+                .map(|side| {
+                    let (profile, width) = side_profile(tile.voxels(), *side);
+                    (*side, sockets.socket(profile, width))
+                })
+                .collect()
+        })
+        .collect();
+    let tops: Vec<Vec<bool>> = tiles
+        .iter()
+        .map(|tile| layer(tile.voxels(), tile.voxels().height() - 1))
+        .collect();
+    let bottoms: Vec<Vec<bool>> = tiles.iter().map(|tile| layer(tile.voxels(), 0)).collect();
+
+    let mut updates = HashMap::new();
+    for source_id in 0..tiles.len() {
+        for side in SIDES {
+            let update = (0..tiles.len())
+                .map(|target_id| {
+                    let target = &sides[target_id][&side.opposite()];
+                    if sides[source_id][&side].connects(target) { 1.0 } else { BAN }
+                })
+                .collect();
+            updates.insert((source_id as u32, side), update);
+        }
+        let up = (0..tiles.len())
+            .map(|target_id| if rests_on(&bottoms[target_id], &tops[source_id]) { 1.0 } else { BAN })
+            .collect();
+        updates.insert((source_id as u32, Direction::Up), up);
+        let down = (0..tiles.len())
+            .map(|target_id| if rests_on(&bottoms[source_id], &tops[target_id]) { 1.0 } else { BAN })
+            .collect();
+        updates.insert((source_id as u32, Direction::Down), down);
+    }
+    updates
+}
+
+fn rests_on(bottom: &[bool], top: &[bool]) -> bool {
+    bottom.iter().zip(top).all(|(above, below)| !*above || *below)
+}
+
+fn solid(voxel: &Voxel) -> bool {
+    !model::is_empty(voxel)
+}
+
+// Solid voxels of the side face in direction, row by row from the bottom,
+// along with the row width
+fn side_profile(voxels: &Grid<Voxel>, side: Direction) -> (Vec<Option<Voxel>>, usize) {
+    let (width, depth, height) = (voxels.width(), voxels.depth(), voxels.height());
+    let row_width = match side {
+        Direction::East | Direction::West => depth,
+        _ => width,
+    };
+    let mut profile = Vec::with_capacity((row_width * height) as usize);
+    for z in 0..height {
+        for u in 0..row_width {
+            let (x, y) = match side {
+                Direction::East => (width - 1, u),
+                Direction::West => (0, depth - 1 - u),
+                Direction::North => (width - 1 - u, depth - 1),
+                _ => (u, 0),
+            };
+            let voxel = voxels.get(x, y, z);
+            profile.push(if solid(voxel) { Some(*voxel) } else { None });
+        }
+    }
+    (profile, row_width as usize)
+}
+
+fn layer(voxels: &Grid<Voxel>, z: u32) -> Vec<bool> {
+    let mut layer = Vec::with_capacity((voxels.width() * voxels.depth()) as usize);
+    for y in 0..voxels.depth() {
+        for x in 0..voxels.width() {
+            layer.push(solid(voxels.get(x, y, z)));
+        }
+    }
+    layer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tile::{Template, TileSet};
+
+    #[test]
+    fn sides_match_colours_and_tops_match_solids() {
+        let tileset = TileSet::infer(Template::Road);
+        let id = |name: &str| tileset.find(name).unwrap();
+        let allows = |source: &str, direction: Direction, target: &str| {
+            tileset.update(id(source), direction)[id(target) as usize] >= 0.0
+        };
+        // Dirt and grass are both solid, but the grass is green on top
+        assert!(allows("dirt", Direction::East, "dirt"));
+        assert!(!allows("dirt", Direction::East, "grass"));
+        assert!(allows("grass", Direction::North, "grass"));
+        // Brown dirt still carries green grass
+        assert!(allows("dirt", Direction::Up, "grass"));
+        assert!(!allows("sky", Direction::Up, "grass"));
+        // The grey road surface continues into the next road tile
+        assert!(allows("road-inner", Direction::East, "road-inner"));
+        assert!(!allows("road-inner", Direction::East, "sky"));
+    }
+}
@@ -1,25 +1,40 @@
 use std::fmt::{self, Display, Formatter};
 use std::collections::HashMap;
 
-use crate::{model, map::Direction};
+use crate::{model, socket, map::{quarter_turns, Direction, DIAGONALS, FACES}};
 use sol_grid::{Grid, Rotation, Voxel};
 
+pub const ROTATIONS: [Rotation; 4] = [
+    Rotation::R0,
+    Rotation::R90,
+    Rotation::R180,
+    Rotation::R270,
+];
+
 pub struct Tile {
     name: String,
     voxels: Grid<Voxel>,
     tag: Tag,
     orientation: Orientation,
+    frequency: f32,
+    symmetry: Symmetry,
+    // Whether the tile may also be rotated about x and y, pointing its top
+    // in any of the six directions
+    tumbles: bool,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Tag {
+    Bedrock,
     Dirt,
     Grass,
     Sky,
     Road,
+    // Tiles extracted from an example by the overlapping model
+    Pattern,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Orientation {
     Invariant,
     Edge(Direction),
@@ -28,25 +43,96 @@ pub enum Orientation {
 
 impl Orientation {
     fn rotated_z(&self, rotation: &Rotation) -> Self {
+        self.mapped(|d| d.rotated_z(rotation))
+    }
+
+    fn rotated_x(&self, rotation: &Rotation) -> Self {
+        self.mapped(|d| d.rotated_x(rotation))
+    }
+
+    fn rotated_y(&self, rotation: &Rotation) -> Self {
+        self.mapped(|d| d.rotated_y(rotation))
+    }
+
+    fn mirrored_x(&self) -> Self {
+        self.mapped(|d| d.mirrored_x())
+    }
+
+    fn mapped(&self, f: impl Fn(&Direction) -> Direction) -> Self {
         match self {
-            Self::Edge(d) => Self::Edge(d.rotated_z(rotation)),
-            Self::Corner(d) => Self::Corner(d.rotated_z(rotation)),
+            Self::Edge(d) => Self::Edge(f(d)),
+            Self::Corner(d) => Self::Corner(f(d)),
             other => *other,
         }
     }
 }
 
+// Symmetry classes of the classic simple tiled model, named after letters
+// with the same symmetries, deciding which rotations and reflections about z
+// are distinct
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symmetry {
+    // Unchanged by any rotation or reflection, one variant
+    X,
+    // Two variants, straight pieces like pipes
+    I,
+    // Two variants, symmetric about a diagonal
+    Backslash,
+    // Four rotations, symmetric about one axis
+    T,
+    // Four rotations, symmetric about a diagonal
+    L,
+    // Four rotations and their mirror images
+    F,
+}
+
+impl Symmetry {
+    fn of(orientation: &Orientation) -> Self {
+        match orientation {
+            Orientation::Invariant => Self::X,
+            Orientation::Edge(_) => Self::T,
+            Orientation::Corner(_) => Self::L,
+        }
+    }
+}
+
 impl Display for Orientation {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
-            Self::Edge(d) => write!(f, "{}", format!("{:?}", d).to_lowercase()),
-            Self::Corner(d) => write!(f, "{}", format!("{:?}", d).to_lowercase()),
+            Self::Edge(d) => write!(f, "{}", d),
+            Self::Corner(d) => write!(f, "{}", d),
             _ => write!(f, "{}", format!("{:?}", self).to_lowercase()),
         }
     }
 }
 
 impl Tile {
+    pub fn new(
+        name: String,
+        voxels: Grid<Voxel>,
+        tag: Tag,
+        orientation: Orientation,
+        frequency: f32,
+    ) -> Self {
+        Self {
+            name: name,
+            voxels: voxels,
+            tag: tag,
+            orientation: orientation,
+            frequency: frequency,
+            symmetry: Symmetry::of(&orientation),
+            tumbles: false,
+        }
+    }
+
+    pub fn with_symmetry(self, symmetry: Symmetry) -> Self {
+        Self { symmetry: symmetry, ..self }
+    }
+
+    pub fn with_tumbling(self, tumbles: bool) -> Self {
+        Self { tumbles: tumbles, ..self }
+    }
+
     pub fn name(&self) -> &String {
         &self.name
     }
@@ -63,168 +149,429 @@ impl Tile {
         &self.orientation
     }
 
+    pub fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
     pub fn rotated_z(&self, rotation: &Rotation) -> Self {
-        let voxels = self.voxels().rotated_z(rotation);
         let orientation = self.orientation().rotated_z(rotation);
+        let name = match orientation {
+            Orientation::Invariant => format!("{}-{}", self.name, quarter_turns(rotation) * 90),
+            _ => format!("{}-{}", self.name, orientation),
+        };
+        self.transformed(name, self.voxels().rotated_z(rotation), orientation)
+    }
+
+    pub fn rotated_x(&self, rotation: &Rotation) -> Self {
+        self.transformed(
+            format!("{}-x{}", self.name, quarter_turns(rotation) * 90),
+            model::rotated_x(self.voxels(), rotation),
+            self.orientation().rotated_x(rotation),
+        )
+    }
+
+    pub fn rotated_y(&self, rotation: &Rotation) -> Self {
+        self.transformed(
+            format!("{}-y{}", self.name, quarter_turns(rotation) * 90),
+            model::rotated_y(self.voxels(), rotation),
+            self.orientation().rotated_y(rotation),
+        )
+    }
+
+    pub fn mirrored_x(&self) -> Self {
+        self.transformed(
+            format!("{}-mirrored-x", self.name),
+            model::mirrored_x(self.voxels()),
+            self.orientation().mirrored_x(),
+        )
+    }
+
+    fn transformed(&self, name: String, voxels: Grid<Voxel>, orientation: Orientation) -> Self {
         Self {
-            name: format!("{}-{}", self.name, orientation),
+            name: name,
             voxels: voxels,
-            tag: *self.tag(),
+            tag: self.tag,
             orientation: orientation,
+            frequency: self.frequency,
+            symmetry: self.symmetry,
+            tumbles: self.tumbles,
         }
     }
+
+    // Whether both tiles look and face the same way
+    pub fn same_as(&self, other: &Tile) -> bool {
+        let (a, b) = (self.voxels(), other.voxels());
+        self.orientation == other.orientation
+            && (a.width(), a.depth(), a.height()) == (b.width(), b.depth(), b.height())
+            && a.enumerate_cells().zip(b.enumerate_cells()).all(|(a, b)| a.3 == b.3)
+    }
+
+    // Distinct variants of the tile under its symmetry class, rotations about
+    // z first in ROTATIONS order, then tilted copies for tumbling tiles
+    pub fn variants(self) -> Vec<Tile> {
+        let tumbles = self.tumbles;
+        let upright = match self.symmetry {
+            Symmetry::X => vec![self],
+            Symmetry::I | Symmetry::Backslash => {
+                ROTATIONS[..2].iter().map(|rotation| self.rotated_z(rotation)).collect()
+            }
+            Symmetry::T | Symmetry::L => {
+                ROTATIONS.iter().map(|rotation| self.rotated_z(rotation)).collect()
+            }
+            Symmetry::F => {
+                let mirrored = self.mirrored_x();
+                ROTATIONS
+                    .iter()
+                    .map(|rotation| self.rotated_z(rotation))
+                    .chain(ROTATIONS.iter().map(|rotation| mirrored.rotated_z(rotation)))
+                    .collect()
+            }
+        };
+        if !tumbles {
+            return upright;
+        }
+        let mut variants: Vec<Tile> = Vec::new();
+        for tile in upright.iter() {
+            let tilted = [
+                tile.rotated_x(&Rotation::R90),
+                tile.rotated_x(&Rotation::R180),
+                tile.rotated_x(&Rotation::R270),
+                tile.rotated_y(&Rotation::R90),
+                tile.rotated_y(&Rotation::R270),
+            ];
+            for variant in tilted {
+                let duplicate = upright.iter().chain(variants.iter()).any(|v| v.same_as(&variant));
+                if !duplicate {
+                    variants.push(variant);
+                }
+            }
+        }
+        upright.into_iter().chain(variants).collect()
+    }
 }
 
 pub struct TileSet {
-    seed_id: u32,
     tiles: Vec<Tile>,
+    frequencies: Vec<f32>,
     updates: HashMap<(u32, Direction), Vec<f32>>,
+    allowed: HashMap<(u32, Direction), Vec<u32>>,
 }
 
 impl TileSet {
+    pub fn new(tiles: Vec<Tile>, updates: HashMap<(u32, Direction), Vec<f32>>) -> Self {
+        let allowed = allowed(&updates);
+        let frequencies = tiles.iter().map(|tile| tile.frequency()).collect();
+        Self {
+            frequencies: frequencies,
+            tiles: tiles,
+            updates: updates,
+            allowed: allowed,
+        }
+    }
+
     pub fn gen(template: Template) -> Self {
         let mut tiles = Vec::new();
         for tile in template.tiles() {
-            tiles.extend(
-                match tile.orientation {
-                    Orientation::Invariant => vec![tile],
-                    _ => vec![
-                        tile.rotated_z(&Rotation::R0),
-                        tile.rotated_z(&Rotation::R90),
-                        tile.rotated_z(&Rotation::R180),
-                        tile.rotated_z(&Rotation::R270),
-                    ],
-                }
-            );
+            tiles.extend(tile.variants());
         }
         let mut updates = HashMap::new();
-        let directions = [
-            Direction::East,
-            Direction::West,
-            Direction::North,
-            Direction::South,
-        ];
-        const BAN: f32 = -1.0;
         for (id, source) in tiles.iter().enumerate() {
-            for direction in directions {
-                let mut update = vec![0.0; tiles.len()];
-                for target in &tiles {
-                    update.push(
-                        match source.tag() {
-                            Tag::Dirt => match target.tag() {
-                                Tag::Dirt => if direction.is_horizontal() { 0.8 } else { BAN },
-                                Tag::Sky => if matches!(direction, Direction::Up) {
-                                    1.0
-                                } else if direction.is_horizontal() {
-                                    0.2
-                                } else {
-                                    BAN
-                                },
-                                // Tag::Grass => if matches!(direction, Direction::Up) { 0.1 } else { BAN },
-                                _ => BAN,
-                            },
-                            _ => BAN,
-                            // Tag::Grass => match target.tag() {
-                            //     Tag::Grass => if direction.is_horizontal() { 0.1 } else { BAN },
-                            //     Tag::Sky => if matches!(direction, Direction::Up) { 0.1 } else { BAN },
-                            //     Tag::Road => if matches!(direction, Direction::Up) { 0.1 } else { BAN },
-                            //     _ => BAN,
-                            // },
-                            // Tag::Sky => match target.tag() {
-                            //     Tag::Sky => 0.1,
-                            //     Tag::Road => match (direction, target.orientation()) {
-                            //         (d, Orientation::Edge(t)) => if d == *t { 0.1 } else { BAN },
-                            //         (
-                            //             Direction::East,
-                            //             Orientation::Corner(Direction::NorthWest)
-                            //             | Orientation::Corner(Direction::SouthWest)
-                            //         ) => 0.1,
-                            //         (
-                            //             Direction::West,
-                            //             Orientation::Corner(Direction::NorthEast)
-                            //             | Orientation::Corner(Direction::SouthEast)
-                            //         ) => 0.1,
-                            //         (
-                            //             Direction::North,
-                            //             Orientation::Corner(Direction::SouthEast)
-                            //             | Orientation::Corner(Direction::SouthWest)
-                            //         ) => 0.1,
-                            //         (
-                            //             Direction::South,
-                            //             Orientation::Corner(Direction::NorthEast)
-                            //             | Orientation::Corner(Direction::NorthWest)
-                            //         ) => 0.1,
-                            //         _ => BAN,
-                            //     },
-                            //     _ => BAN,
-                            // },
-                            // Tag::Road => match target.tag() {
-                            //     Tag::Road => {
-                            //         match (source.orientation(), direction, target.orientation()) {
-                            //             (Orientation::Invariant, _, Orientation::Invariant) => 0.1,
-                            //             (Orientation::Invariant, d, Orientation::Edge(t)) => {
-                            //                 if d == t.rotated_z(&Rotation::R180) {
-                            //                     0.1
-                            //                 } else {
-                            //                     BAN
-                            //                 }
-                            //             },
-                            //             (Orientation::Edge(s), d, Orientation::Edge(t)) => {
-                            //                 if *s == d && *t == s.rotated_z(&Rotation::R180) {
-                            //                     0.1
-                            //                 } else if s.is_perpendicular(d) && *s == *t {
-                            //                     0.1
-                            //                 } else {
-                            //                     BAN
-                            //                 }
-                            //             },
-                            //             // TODO Finish constraints
-                            //             (Orientation::Edge(s), d, Orientation::Corner(t)) => {
-                            //                 if *s == d && *t == s.rotated_z(&Rotation::R180) {
-                            //                     0.1
-                            //                 } else if s.is_perpendicular(d) && *s == *t {
-                            //                     0.1
-                            //                 } else {
-                            //                     BAN
-                            //                 }
-                            //             },
-                            //             _ => BAN,
-                            //         }
-                            //     },
-                            //     _ => BAN,
-                            // },
-                        }
-                    );
-                }
+            for direction in FACES.into_iter().chain(DIAGONALS) {
+                let update = tiles
+                    .iter()
+                    .map(|target| weight(source, direction, target))
+                    .collect();
                 updates.insert((id as u32, direction), update);
             }
         }
-        Self {
-            seed_id: 0,
-            tiles: tiles,
-            updates: updates,
+        Self::new(tiles, updates)
+    }
+
+    // Like gen, but with adjacency inferred from the tile voxels instead of
+    // the template's rules
+    pub fn infer(template: Template) -> Self {
+        let mut tiles = Vec::new();
+        for tile in template.tiles() {
+            tiles.extend(tile.variants());
         }
+        let updates = socket::updates(&tiles);
+        Self::new(tiles, updates)
     }
 
     pub fn len(&self) -> usize {
         self.tiles.len()
     }
 
-    pub fn seed_id(&self) -> u32 {
-        self.seed_id
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    // Prior weight of each tile, relative to the others
+    pub fn frequencies(&self) -> &Vec<f32> {
+        &self.frequencies
+    }
+
+    pub fn name(&self, tile_id: u32) -> &String {
+        self.tiles[tile_id as usize].name()
+    }
+
+    // Like gen, but with every weight made to agree with its mirror image.
+    // A template sets the weight of every pair, so pairs that disagree are
+    // settled by resolution
+    pub fn gen_symmetric(template: Template, resolution: Resolution) -> Result<Self, Conflict> {
+        Self::gen(template).symmetric(|_, _, _| true, resolution)
+    }
+
+    // Makes every weight agree with its mirror image. declared tells the
+    // weights set on purpose from those left at a default, which take the
+    // weight of their mirror image instead of being resolved
+    pub fn symmetric(
+        self,
+        declared: impl Fn(u32, Direction, u32) -> bool,
+        resolution: Resolution,
+    ) -> Result<TileSet, Conflict> {
+        let mut updates = self.updates;
+        symmetric(&mut updates, declared, resolution)?;
+        Ok(TileSet::new(self.tiles, updates))
+    }
+
+    // Id of the tile or variant with the given name, for anchoring cells
+    pub fn find(&self, name: &str) -> Option<u32> {
+        self.tiles.iter().position(|tile| tile.name() == name).map(|id| id as u32)
+    }
+
+    // Whether there are rules for the neighbours in direction, without them
+    // any tile may go there
+    pub fn constrains(&self, direction: Direction) -> bool {
+        self.updates.keys().any(|(_, d)| *d == direction)
     }
 
     pub fn update(&self, tile_id: u32, direction: Direction) -> &Vec<f32> {
         &self.updates[&(tile_id, direction)]
     }
 
+    // Tiles that the source allows in its neighbour in direction
+    pub fn allowed(&self, source_id: u32, direction: Direction) -> &Vec<u32> {
+        &self.allowed[&(source_id, direction)]
+    }
+
     pub fn voxels(&self, tile_id: u32) -> &Grid<Voxel> {
-        &self.tiles[tile_id as usize].voxels()
+        self.tiles[tile_id as usize].voxels()
     }
 }
 
+pub const BAN: f32 = -1.0;
 
-#[derive(Debug)]
+// Update weight for target being the neighbour of source in direction
+fn weight(source: &Tile, direction: Direction, target: &Tile) -> f32 {
+    match direction {
+        Direction::Up => stack_weight(source, target),
+        Direction::Down => stack_weight(target, source),
+        direction if direction.is_face() => side_weight(source, direction, target),
+        _ => corner_weight(source, direction, target),
+    }
+}
+
+// Weight for top resting directly on bottom
+fn stack_weight(bottom: &Tile, top: &Tile) -> f32 {
+    match bottom.tag() {
+        Tag::Bedrock => match top.tag() {
+            Tag::Dirt => 1.0,
+            _ => BAN,
+        },
+        Tag::Dirt => match top.tag() {
+            Tag::Dirt => 0.5,
+            Tag::Grass => 0.5,
+            _ => BAN,
+        },
+        Tag::Grass => match top.tag() {
+            Tag::Sky => 0.8,
+            Tag::Road => 0.2,
+            _ => BAN,
+        },
+        Tag::Road | Tag::Sky => match top.tag() {
+            Tag::Sky => 1.0,
+            _ => BAN,
+        },
+        Tag::Pattern => BAN,
+    }
+}
+
+fn side_weight(source: &Tile, direction: Direction, target: &Tile) -> f32 {
+    let source_side = side(source, direction);
+    let target_side = side(target, direction.opposite());
+    match (source.tag(), target.tag()) {
+        (Tag::Bedrock, Tag::Bedrock) => 1.0,
+        (Tag::Bedrock, _) | (_, Tag::Bedrock) => BAN,
+        (Tag::Road, Tag::Road) => if source_side == target_side { 0.5 } else { BAN },
+        (Tag::Road, _) | (_, Tag::Road) => {
+            if source_side == Side::Open && target_side == Side::Open { 0.2 } else { BAN }
+        },
+        (s, t) => if s == t { 0.8 } else { 0.2 },
+    }
+}
+
+// Weight for two tiles meeting at a vertical edge only, which is either
+// covered by road on both sides or on neither
+fn corner_weight(source: &Tile, direction: Direction, target: &Tile) -> f32 {
+    if covers(source, direction) == covers(target, direction.opposite()) { 0.0 } else { BAN }
+}
+
+// Whether road covers the corner of a tile towards a diagonal direction
+fn covers(tile: &Tile, direction: Direction) -> bool {
+    if *tile.tag() != Tag::Road {
+        return false;
+    }
+    match tile.orientation() {
+        Orientation::Invariant => true,
+        Orientation::Edge(outside) => !direction.faces().contains(outside),
+        Orientation::Corner(outside) => direction == outside.opposite(),
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum Side {
+    Open,
+    Road,
+    // Road covers the half of the side towards the direction
+    Half(Direction),
+}
+
+// Road surface along the side of a tile facing direction
+fn side(tile: &Tile, direction: Direction) -> Side {
+    if *tile.tag() != Tag::Road {
+        return Side::Open;
+    }
+    match tile.orientation() {
+        Orientation::Invariant => Side::Road,
+        Orientation::Edge(outside) => {
+            if direction == *outside {
+                Side::Open
+            } else if direction == outside.opposite() {
+                Side::Road
+            } else {
+                Side::Half(outside.opposite())
+            }
+        },
+        Orientation::Corner(outside) => {
+            let faces = outside.faces();
+            let (a, b) = (faces[0], faces[1]);
+            if direction == a || direction == b {
+                Side::Open
+            } else if direction == a.opposite() {
+                Side::Half(b.opposite())
+            } else {
+                Side::Half(a.opposite())
+            }
+        },
+    }
+}
+
+// Hard constraint view of the updates: a target may sit next to a source
+// only if neither tile bans the other across the shared face or edge
+fn allowed(updates: &HashMap<(u32, Direction), Vec<f32>>) -> HashMap<(u32, Direction), Vec<u32>> {
+    let mut allowed = HashMap::new();
+    for ((source_id, direction), update) in updates.iter() {
+        let targets = update
+            .iter()
+            .enumerate()
+            .filter(|(target_id, weight)| {
+                let reverse = updates.get(&(*target_id as u32, direction.opposite()));
+                **weight >= 0.0
+                    && reverse.is_none_or(|reverse| reverse[*source_id as usize] >= 0.0)
+            })
+            .map(|(target_id, _)| target_id as u32)
+            .collect();
+        allowed.insert((*source_id, *direction), targets);
+    }
+    allowed
+}
+
+// How symmetric settles a rule and its mirror when they disagree
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    // The lower weight, so a ban on either side wins
+    Min,
+    // The higher weight, so an allowed pair on either side wins
+    Max,
+    Error,
+}
+
+// Weight of target next to source in direction that disagrees with the
+// weight of source next to target in the opposite direction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub source: u32,
+    pub direction: Direction,
+    pub target: u32,
+    pub forward: f32,
+    pub backward: f32,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "tile {} weighs tile {} {} at {}, but the reverse rule weighs it at {}",
+            self.source,
+            self.target,
+            self.direction.relative(),
+            self.forward,
+            self.backward,
+        )
+    }
+}
+
+// Makes the weight of target next to source in direction equal to the weight
+// of source next to target in the opposite direction. When only one of the
+// two rules is declared the other one takes its weight, otherwise the two are
+// settled by resolution
+pub fn symmetric(
+    updates: &mut HashMap<(u32, Direction), Vec<f32>>,
+    declared: impl Fn(u32, Direction, u32) -> bool,
+    resolution: Resolution,
+) -> Result<(), Conflict> {
+    let mut keys: Vec<(u32, Direction)> = updates.keys().copied().collect();
+    keys.sort_by_key(|(source_id, direction)| (*source_id, *direction as usize));
+    for (source_id, direction) in keys {
+        let tile_count = updates[&(source_id, direction)].len() as u32;
+        // Each pair is visited from its lower tile id, or from either side
+        // for a tile next to itself
+        for target_id in source_id..tile_count {
+            let opposite = direction.opposite();
+            let backward = match updates.get(&(target_id, opposite)) {
+                Some(update) => update[source_id as usize],
+                None => continue,
+            };
+            let forward = updates[&(source_id, direction)][target_id as usize];
+            if forward == backward {
+                continue;
+            }
+            let weight = match (declared(source_id, direction, target_id), declared(target_id, opposite, source_id)) {
+                (true, false) => forward,
+                (false, true) => backward,
+                _ => match resolution {
+                    Resolution::Min => forward.min(backward),
+                    Resolution::Max => forward.max(backward),
+                    Resolution::Error => {
+                        return Err(Conflict {
+                            source: source_id,
+                            direction: direction,
+                            target: target_id,
+                            forward: forward,
+                            backward: backward,
+                        })
+                    }
+                },
+            };
+            updates.get_mut(&(source_id, direction)).unwrap()[target_id as usize] = weight;
+            updates.get_mut(&(target_id, opposite)).unwrap()[source_id as usize] = weight;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum Template {
     Road,
 }
@@ -234,45 +581,209 @@ impl Template {
         match *self {
             Template::Road => {
                 let (width, depth, height) = (3, 3, 3);
+                // Roads make up about 5% of the surface next to grass, shared
+                // between the nine road variants
+                let road = 0.05 / 0.95 / 9.0;
                 vec![
+                    Tile {
+                        name: "bedrock".to_string(),
+                        voxels: model::gen::bedrock(width, depth, height),
+                        tag: Tag::Bedrock,
+                        orientation: Orientation::Invariant,
+                        frequency: 1.0,
+                        symmetry: Symmetry::X,
+                        tumbles: false,
+                    },
                     Tile {
                         name: "dirt".to_string(),
                         voxels: model::gen::dirt(width, depth, height),
                         tag: Tag::Dirt,
                         orientation: Orientation::Invariant,
+                        frequency: 1.0,
+                        symmetry: Symmetry::X,
+                        tumbles: false,
                     },
                     Tile {
                         name: "grass".to_string(),
                         voxels: model::gen::grass(width, depth, height),
                         tag: Tag::Grass,
                         orientation: Orientation::Invariant,
+                        frequency: 1.0,
+                        symmetry: Symmetry::X,
+                        tumbles: false,
                     },
                     Tile {
                         name: "road-inner".to_string(),
                         voxels: model::gen::road_inner(width, depth, height),
                         tag: Tag::Road,
                         orientation: Orientation::Invariant,
+                        frequency: road,
+                        symmetry: Symmetry::X,
+                        tumbles: false,
                     },
                     Tile {
                         name: "road-edge".to_string(),
                         voxels: model::gen::road_edge(width, depth, height),
                         tag: Tag::Road,
                         orientation: Orientation::Edge(Direction::West),
+                        frequency: road,
+                        symmetry: Symmetry::T,
+                        tumbles: false,
                     },
                     Tile {
                         name: "road-corner".to_string(),
                         voxels: model::gen::road_corner(width, depth, height),
                         tag: Tag::Road,
                         orientation: Orientation::Corner(Direction::NorthEast),
+                        frequency: road,
+                        symmetry: Symmetry::L,
+                        tumbles: false,
                     },
                     Tile {
                         name: "sky".to_string(),
                         voxels: model::gen::sky(width, depth, height),
                         tag: Tag::Sky,
                         orientation: Orientation::Invariant,
+                        frequency: 1.0,
+                        symmetry: Symmetry::X,
+                        tumbles: false,
                     },
                 ]
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::tests::patterns;
+
+    // Two tiles where a weighs b to the east at 0.5 and b bans a to the
+    // west, every other pair weighing 1
+    fn lopsided() -> TileSet {
+        patterns(&[("a", 1.0), ("b", 1.0)], &FACES, |source, direction, target| {
+            match (source, direction, target) {
+                (0, Direction::East, 1) => 0.5,
+                (1, Direction::West, 0) => BAN,
+                _ => 1.0,
+            }
+        })
+    }
+
+    fn pair(tileset: &TileSet) -> (f32, f32) {
+        (tileset.update(0, Direction::East)[1], tileset.update(1, Direction::West)[0])
+    }
+
+    // A 3x3x3 tile with the given voxels coloured, the rest left empty
+    fn shape(voxels: &[((u32, u32, u32), u8)]) -> Tile {
+        let mut grid = Grid::new(3, 3, 3);
+        for ((x, y, z), colour) in voxels.iter().copied() {
+            *grid.get_mut(x, y, z) = Voxel::from_rgba(&[colour, 0, 0, 0xff]);
+        }
+        Tile::new("shape".to_string(), grid, Tag::Pattern, Orientation::Invariant, 1.0)
+    }
+
+    // A corner voxel and its neighbours along x, y and z in different
+    // colours, which no rotation or reflection leaves unchanged
+    fn asymmetric() -> Tile {
+        shape(&[((0, 0, 0), 1), ((1, 0, 0), 2), ((0, 1, 0), 3), ((0, 0, 1), 4)])
+    }
+
+    #[test]
+    fn symmetry_classes_decide_the_variant_count() {
+        let counts = [
+            (Symmetry::X, 1),
+            (Symmetry::I, 2),
+            (Symmetry::Backslash, 2),
+            (Symmetry::T, 4),
+            (Symmetry::L, 4),
+            (Symmetry::F, 8),
+        ];
+        for (symmetry, count) in counts {
+            let variants = asymmetric().with_symmetry(symmetry).variants();
+            assert_eq!(variants.len(), count, "{:?}", symmetry);
+        }
+    }
+
+    #[test]
+    fn tumbling_drops_duplicate_variants() {
+        let tumbling = |tile: Tile, symmetry| tile.with_symmetry(symmetry).with_tumbling(true).variants().len();
+        // A full cube looks the same every way up
+        let cube: Vec<((u32, u32, u32), u8)> =
+            (0..27).map(|i| ((i % 3, i / 3 % 3, i / 9), 1)).collect();
+        assert_eq!(tumbling(shape(&cube), Symmetry::X), 1);
+        // A column with a coloured top only differs by where its top points
+        let column = [((1, 1, 0), 1), ((1, 1, 1), 1), ((1, 1, 2), 2)];
+        assert_eq!(tumbling(shape(&column), Symmetry::X), 6);
+        assert_eq!(tumbling(asymmetric(), Symmetry::T), 24);
+    }
+
+    #[test]
+    fn tilting_moves_voxels_like_directions() {
+        // The cell next to the centre of a 3x3x3 grid in direction
+        let at = |direction: Direction| {
+            let (dx, dy, dz) = direction.offset();
+            ((1 + dx) as u32, (1 + dy) as u32, (1 + dz) as u32)
+        };
+        let marked = Voxel::from_rgba(&[1, 0, 0, 0xff]);
+        for direction in FACES {
+            let voxels = shape(&[(at(direction), 1)]).voxels().clone();
+            for rotation in ROTATIONS.iter() {
+                let tilted = [
+                    (model::rotated_x(&voxels, rotation), direction.rotated_x(rotation)),
+                    (model::rotated_y(&voxels, rotation), direction.rotated_y(rotation)),
+                ];
+                for (grid, direction) in tilted {
+                    let (x, y, z) = at(direction);
+                    assert_eq!(*grid.get(x, y, z), marked, "{} turned {:?}", direction, rotation);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resolves_disagreeing_weights() {
+        let everything = |_, _, _| true;
+        let min = lopsided().symmetric(everything, Resolution::Min).unwrap();
+        assert_eq!(pair(&min), (BAN, BAN));
+        let max = lopsided().symmetric(everything, Resolution::Max).unwrap();
+        assert_eq!(pair(&max), (0.5, 0.5));
+        match lopsided().symmetric(everything, Resolution::Error) {
+            Err(conflict) => assert_eq!(
+                conflict,
+                Conflict {
+                    source: 0,
+                    direction: Direction::East,
+                    target: 1,
+                    forward: 0.5,
+                    backward: BAN,
+                }
+            ),
+            Ok(_) => panic!("conflict accepted"),
+        }
+        // Weights that agree are left alone
+        assert_eq!(max.update(0, Direction::North), &vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn copies_declared_weights() {
+        let east = |source_id, direction, _| (source_id, direction) == (0, Direction::East);
+        let tileset = lopsided().symmetric(east, Resolution::Error).unwrap();
+        assert_eq!(pair(&tileset), (0.5, 0.5));
+        let west = |source_id, direction, _| (source_id, direction) == (1, Direction::West);
+        let tileset = lopsided().symmetric(west, Resolution::Error).unwrap();
+        assert_eq!(pair(&tileset), (BAN, BAN));
+    }
+
+    #[test]
+    fn road_template_is_symmetric() {
+        let tileset = TileSet::gen(Template::Road);
+        let symmetric = TileSet::gen_symmetric(Template::Road, Resolution::Error).unwrap();
+        for tile_id in 0..tileset.len() as u32 {
+            for direction in FACES.into_iter().chain(DIAGONALS) {
+                assert_eq!(tileset.update(tile_id, direction), symmetric.update(tile_id, direction));
+            }
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use sol_grid::{Grid, Voxel};
+
+use crate::map::{render, Anchor, Boundary, GenerationError, Map, Neighbourhood, Options, Propagation};
+use crate::batch;
+use crate::math::mix;
+use crate::tile::TileSet;
+
+// Chunked generation of an unbounded world, chunks are width x depth columns
+// of the full height laid out on an integer (x, y) grid.
+//
+// Chunk borders lie on a frame of lines at every multiple of the chunk width
+// and depth. The frame is generated piecewise, first the column at the
+// corners where four chunks meet, then each seam between two corners
+// anchored on them, and a chunk is generated last anchored on the four seams
+// around it. Every piece is seeded from the world seed and its own coordinate
+// and only depends on the pieces it is anchored on, so a chunk comes out the
+// same no matter which chunks were generated before it, and two neighbouring
+// chunks both agree with the seam they share.
+//
+// All corners share one column. Independent corners could disagree on layers
+// that have to line up across the whole world, like the bedrock under the
+// Road template, and leave the seam between them without a solution.
+
+// Tiles of a generated chunk, including its south and west seams, or of any
+// other fully generated box
+pub struct Chunk {
+    width: u32,
+    depth: u32,
+    height: u32,
+    tileset: Arc<TileSet>,
+    tiles: Vec<u32>,
+}
+
+impl Chunk {
+    pub fn new(
+        width: u32,
+        depth: u32,
+        height: u32,
+        tileset: Arc<TileSet>,
+        tiles: Vec<u32>,
+    ) -> Chunk {
+        Chunk {
+            width: width,
+            depth: depth,
+            height: height,
+            tileset: tileset,
+            tiles: tiles,
+        }
+    }
+
+    pub fn tile(&self, x: u32, y: u32, z: u32) -> u32 {
+        self.tiles[((z * self.depth + y) * self.width + x) as usize]
+    }
+
+    pub fn voxels(&self) -> Grid<Voxel> {
+        render(&self.tileset, self.width, self.depth, self.height, |x, y, z| {
+            Some(self.tile(x, y, z))
+        })
+    }
+}
+
+// Salts that keep the seeds of the different pieces apart
+const CORNER: u64 = 1;
+const SOUTH: u64 = 2;
+const WEST: u64 = 3;
+const INTERIOR: u64 = 4;
+
+pub struct World {
+    width: u32,
+    depth: u32,
+    height: u32,
+    tileset: Arc<TileSet>,
+    seed: u64,
+    options: Options,
+    // Generated frame pieces, each indexed [z * len + i] along its line
+    corner: Option<Vec<u32>>,
+    south: HashMap<(i32, i32), Vec<u32>>,
+    west: HashMap<(i32, i32), Vec<u32>>,
+}
+
+impl World {
+    // The options apply to every piece, except that propagation is always
+    // hard and the horizontal boundaries are always open, see options. Only
+    // neighbours sharing a face may constrain each other, since the seams are
+    // generated apart from the cells diagonal to them
+    pub fn new(
+        width: u32,
+        depth: u32,
+        height: u32,
+        tileset: impl Into<Arc<TileSet>>,
+        seed: u64,
+        options: Options,
+    ) -> Result<World, GenerationError> {
+        if options.neighbourhood != Neighbourhood::Faces {
+            return Err(GenerationError::Invalid(format!(
+                "worlds only constrain neighbours sharing a face, not {:?}",
+                options.neighbourhood
+            )));
+        }
+        let mut options = options;
+        options.propagation = Propagation::Hard;
+        options.anchors = Vec::new();
+        options.boundaries.east = Boundary::Open;
+        options.boundaries.west = Boundary::Open;
+        options.boundaries.north = Boundary::Open;
+        options.boundaries.south = Boundary::Open;
+        Ok(World {
+            width: width,
+            depth: depth,
+            height: height,
+            tileset: tileset.into(),
+            seed: seed,
+            options: options,
+            corner: None,
+            south: HashMap::new(),
+            west: HashMap::new(),
+        })
+    }
+
+    // Options every piece is generated with
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn chunk(&mut self, x: i32, y: i32) -> Result<Chunk, GenerationError> {
+        self.frame(x, y)?;
+        self.interior(x, y)
+    }
+
+    // Generates the chunks at every coordinate on up to threads threads, 0
+    // uses one per core. The seams, which neighbouring chunks share, are
+    // generated first on this thread, so chunks come out the same as from
+    // chunk and in the same order as the coordinates
+    pub fn chunks(
+        &mut self,
+        coordinates: &[(i32, i32)],
+        threads: usize,
+    ) -> Vec<Result<Chunk, GenerationError>> {
+        let results: Vec<Option<Result<Chunk, GenerationError>>> = coordinates
+            .iter()
+            .map(|(x, y)| self.frame(*x, *y).err().map(Err))
+            .collect();
+        let framed: Vec<(i32, i32)> = coordinates
+            .iter()
+            .zip(results.iter())
+            .filter(|(_, result)| result.is_none())
+            .map(|(coordinate, _)| *coordinate)
+            .collect();
+        let world = &*self;
+        let mut chunks = batch::run(&framed, threads, |(x, y)| world.interior(*x, *y)).into_iter();
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or_else(|| chunks.next().unwrap()))
+            .collect()
+    }
+
+    // Generates the four seams around chunk (x, y)
+    fn frame(&mut self, x: i32, y: i32) -> Result<(), GenerationError> {
+        self.south(x, y)?;
+        self.south(x, y + 1)?;
+        self.west(x, y)?;
+        self.west(x + 1, y)?;
+        Ok(())
+    }
+
+    fn interior(&self, x: i32, y: i32) -> Result<Chunk, GenerationError> {
+        let (width, depth) = (self.width, self.depth);
+        let south = &self.south[&(x, y)];
+        let north = &self.south[&(x, y + 1)];
+        let west = &self.west[&(x, y)];
+        let east = &self.west[&(x + 1, y)];
+        // The chunk is generated one cell larger to take in the seams on its
+        // north and east sides, which belong to the neighbouring chunks
+        let mut anchors = Vec::new();
+        for z in 0..self.height {
+            for i in 0..=width {
+                let index = (z * (width + 1) + i) as usize;
+                anchors.push(Anchor::tile(i, 0, z, south[index]));
+                anchors.push(Anchor::tile(i, depth, z, north[index]));
+            }
+            for i in 0..=depth {
+                let index = (z * (depth + 1) + i) as usize;
+                anchors.push(Anchor::tile(0, i, z, west[index]));
+                anchors.push(Anchor::tile(width, i, z, east[index]));
+            }
+        }
+        let map = self.gen(width + 1, depth + 1, anchors, self.salt(INTERIOR, x, y))?;
+        let mut tiles = Vec::with_capacity((width * depth * self.height) as usize);
+        for z in 0..self.height {
+            for y in 0..depth {
+                for x in 0..width {
+                    tiles.push(map.observation(x, y, z)?.unwrap());
+                }
+            }
+        }
+        Ok(Chunk::new(width, depth, self.height, self.tileset.clone(), tiles))
+    }
+
+    // Column at the corners of every chunk
+    fn corner(&mut self) -> Result<Vec<u32>, GenerationError> {
+        if let Some(tiles) = &self.corner {
+            return Ok(tiles.clone());
+        }
+        let map = self.gen(1, 1, Vec::new(), self.salt(CORNER, 0, 0))?;
+        let mut tiles = Vec::with_capacity(self.height as usize);
+        for z in 0..self.height {
+            tiles.push(map.observation(0, 0, z)?.unwrap());
+        }
+        self.corner = Some(tiles.clone());
+        Ok(tiles)
+    }
+
+    // Seam along the south side of chunk (x, y), from its south west corner
+    // to its south east corner
+    fn south(&mut self, x: i32, y: i32) -> Result<Vec<u32>, GenerationError> {
+        if let Some(tiles) = self.south.get(&(x, y)) {
+            return Ok(tiles.clone());
+        }
+        let corner = self.corner()?;
+        let mut anchors = Vec::new();
+        for z in 0..self.height {
+            anchors.push(Anchor::tile(0, 0, z, corner[z as usize]));
+            anchors.push(Anchor::tile(self.width, 0, z, corner[z as usize]));
+        }
+        let map = self.gen(self.width + 1, 1, anchors, self.salt(SOUTH, x, y))?;
+        let mut tiles = Vec::new();
+        for z in 0..self.height {
+            for i in 0..=self.width {
+                tiles.push(map.observation(i, 0, z)?.unwrap());
+            }
+        }
+        self.south.insert((x, y), tiles.clone());
+        Ok(tiles)
+    }
+
+    // Seam along the west side of chunk (x, y), from its south west corner
+    // to its north west corner
+    fn west(&mut self, x: i32, y: i32) -> Result<Vec<u32>, GenerationError> {
+        if let Some(tiles) = self.west.get(&(x, y)) {
+            return Ok(tiles.clone());
+        }
+        let corner = self.corner()?;
+        let mut anchors = Vec::new();
+        for z in 0..self.height {
+            anchors.push(Anchor::tile(0, 0, z, corner[z as usize]));
+            anchors.push(Anchor::tile(0, self.depth, z, corner[z as usize]));
+        }
+        let map = self.gen(1, self.depth + 1, anchors, self.salt(WEST, x, y))?;
+        let mut tiles = Vec::new();
+        for z in 0..self.height {
+            for i in 0..=self.depth {
+                tiles.push(map.observation(0, i, z)?.unwrap());
+            }
+        }
+        self.west.insert((x, y), tiles.clone());
+        Ok(tiles)
+    }
+
+    fn gen(
+        &self,
+        width: u32,
+        depth: u32,
+        anchors: Vec<Anchor>,
+        seed: u64,
+    ) -> Result<Map, GenerationError> {
+        let options = Options {
+            anchors: anchors,
+            ..self.options.clone()
+        };
+        let generation = Map::gen(width, depth, self.height, self.tileset.clone(), seed, options)?;
+        Ok(generation.map)
+    }
+
+    fn salt(&self, piece: u64, x: i32, y: i32) -> u64 {
+        mix(mix(mix(self.seed ^ piece) ^ x as u32 as u64) ^ y as u32 as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::Boundaries;
+    use crate::tile::Template;
+
+    #[test]
+    fn parallel_chunks_match_single_chunks() {
+        let tileset = Arc::new(TileSet::gen(Template::Road));
+        let options = Options {
+            max_backtracks: 16,
+            attempts: 4,
+            ..Options::default()
+        };
+        let coordinates = [(-1, -1), (0, -1), (-1, 0), (0, 0), (3, -2)];
+        let mut parallel = World::new(6, 6, 6, tileset.clone(), 7, options.clone()).unwrap();
+        let chunks = parallel.chunks(&coordinates, 0);
+        // Generated one at a time in the opposite order
+        let mut sequential = World::new(6, 6, 6, tileset, 7, options).unwrap();
+        for ((x, y), chunk) in coordinates.iter().zip(chunks.iter()).rev() {
+            let chunk = chunk.as_ref().unwrap();
+            let expected = sequential.chunk(*x, *y).unwrap();
+            assert_eq!(chunk.tiles, expected.tiles, "chunk ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn rejects_diagonal_neighbourhoods() {
+        let tileset = Arc::new(TileSet::gen(Template::Road));
+        for neighbourhood in [Neighbourhood::Diagonals, Neighbourhood::Full] {
+            let options = Options {
+                neighbourhood: neighbourhood,
+                ..Options::default()
+            };
+            assert!(matches!(
+                World::new(6, 6, 6, tileset.clone(), 7, options),
+                Err(GenerationError::Invalid(_))
+            ));
+        }
+        let options = Options {
+            boundaries: Boundaries::all(Boundary::Periodic),
+            ..Options::default()
+        };
+        let world = World::new(6, 6, 6, tileset, 7, options).unwrap();
+        assert_eq!(world.options().propagation, Propagation::Hard);
+        assert_eq!(world.options().boundaries.east, Boundary::Open);
+        assert_eq!(world.options().boundaries.up, Boundary::Periodic);
+    }
+}